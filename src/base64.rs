@@ -0,0 +1,92 @@
+//! Standard, padded base64, as Duplicati's hashes and restic's keys use it.
+
+#[cfg(feature = "restic")]
+use eyre::{eyre, Result};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(feature = "restic")]
+pub(crate) fn decode(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return Err(eyre!("base64 length {} isn't padded", encoded.len()));
+    }
+
+    let mut out = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let last = i == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(eyre!("misplaced padding in base64"));
+        }
+
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = ALPHABET
+                .iter()
+                .position(|a| a == c)
+                .ok_or_else(|| eyre!("invalid base64 character {:?}", *c as char))?;
+            n = (n << 6) | value as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648's test vectors
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(encode(data.as_bytes()), encoded);
+        }
+        assert_eq!(encode(&[0xfb, 0xff, 0xfe]), "+//+");
+    }
+
+    #[cfg(feature = "restic")]
+    #[test]
+    fn decodes() -> Result<()> {
+        for (data, encoded) in VECTORS {
+            assert_eq!(decode(encoded)?, data.as_bytes());
+        }
+        assert_eq!(decode("+//+")?, [0xfb, 0xff, 0xfe]);
+        assert!(decode("Zm9").is_err());
+        assert!(decode("Zg==Zg==").is_err());
+        assert!(decode("Z!==").is_err());
+        Ok(())
+    }
+}
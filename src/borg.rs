@@ -0,0 +1,275 @@
+//! Backs up into a Borg repository. A [`BorgFloppyDisk`] collects
+//! everything that's copied into it, and on finalize pipes it all into
+//! `borg import-tar` as a tar stream, which becomes one new archive. Going
+//! through tar rather than a temporary directory keeps the source's
+//! ownership, modes, and symlinks.
+//!
+//! `borg create` only archives paths that are on disk, and given `-` it
+//! stores its stdin as a single file, so it can't take a tree from a pipe.
+//! `import-tar` can, and needs borg 1.2 or newer.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use derivative::Derivative;
+use eyre::{eyre, Result};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::staged::{Sink, Stage, StagedFloppyDisk, StagedKind};
+use crate::tar::TarWriter;
+use crate::CopySummary;
+
+pub type BorgFloppyDisk = StagedFloppyDisk<BorgSink>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct BorgSink {
+    borg: PathBuf,
+    repository: String,
+    #[derivative(Debug = "ignore")]
+    passphrase: Option<String>,
+    archive: String,
+    compression: Option<String>,
+}
+
+impl BorgSink {
+    /// `repository` is anything borg accepts, like `/srv/borg` or
+    /// `ssh://user@host/./repo`.
+    pub fn new<S: Into<String>>(repository: S) -> Self {
+        Self {
+            borg: PathBuf::from("borg"),
+            repository: repository.into(),
+            passphrase: None,
+            archive: "{hostname}-{now}".into(),
+            compression: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn borg_program<P: Into<PathBuf>>(mut self, borg: P) -> Self {
+        self.borg = borg.into();
+        self
+    }
+
+    pub fn passphrase<S: Into<String>>(mut self, passphrase: S) -> Self {
+        self.passphrase = Some(passphrase.into());
+        self
+    }
+
+    /// The name for new archives. borg's placeholders work here. Defaults to
+    /// `{hostname}-{now}`.
+    pub fn archive<S: Into<String>>(mut self, archive: S) -> Self {
+        self.archive = archive.into();
+        self
+    }
+
+    /// Passed to `--compression`, eg. `zstd` or `zstd,10`. Defaults to
+    /// borg's own default.
+    pub fn compression<S: Into<String>>(mut self, compression: S) -> Self {
+        self.compression = Some(compression.into());
+        self
+    }
+
+    /// Creates the repository with the given `--encryption` mode, eg.
+    /// `repokey`. Only needed once, before the first copy.
+    pub async fn init(&self, encryption: &str) -> Result<()> {
+        let output = self
+            .command()
+            .args(["init", "--encryption", encryption])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "borg init failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// borg asks before using a repository that's unencrypted and new to
+    /// this machine, or that's moved since it was last used, and it would
+    /// read the answer from the tar on its stdin. Both are refused instead,
+    /// so running borg by hand once is what accepts them.
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.borg);
+        command
+            .env("BORG_REPO", &self.repository)
+            .env("BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK", "no")
+            .env("BORG_RELOCATED_REPO_ACCESS_IS_OK", "no")
+            .kill_on_drop(true);
+        if let Some(passphrase) = &self.passphrase {
+            command.env("BORG_PASSPHRASE", passphrase);
+        }
+        command
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for BorgSink {
+    async fn flush(&self, stage: &Stage<'_>, _summary: &mut CopySummary) -> Result<()> {
+        let mut command = self.command();
+        command.arg("import-tar");
+        if let Some(compression) = &self.compression {
+            command.args(["--compression", compression]);
+        }
+        debug!("borg import-tar of {} entries", stage.entries().len());
+        let mut child = command
+            .arg(format!("::{}", self.archive))
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+
+        let write = async {
+            let mut tar = TarWriter::new(stdin);
+            for entry in stage.entries() {
+                match entry.kind {
+                    StagedKind::File { .. } => tar.append(entry, stage.open(entry).await?).await?,
+                    _ => tar.append(entry, tokio::io::empty()).await?,
+                }
+            }
+            // dropping stdin here is what tells borg the archive is done
+            tar.finish().await?;
+            Ok::<_, eyre::Report>(())
+        };
+        let read_errors = async {
+            let mut errors = String::new();
+            stderr.read_to_string(&mut errors).await?;
+            Ok::<_, eyre::Report>(errors)
+        };
+        let (written, errors) = tokio::join!(write, read_errors);
+        let errors = errors?;
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(eyre!(
+                "borg import-tar failed ({status}): {}",
+                errors.trim()
+            ));
+        }
+        written?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use floppy_disk::mem::MemPermissions;
+    use floppy_disk::prelude::*;
+
+    use super::*;
+    use crate::DiskDrive;
+
+    /// Stands in for `borg` by keeping its arguments, its `BORG_*`
+    /// environment, and whatever it's piped, next to itself.
+    const FAKE_BORG: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+printf '%s\n' "$@" > "$dir/args"
+env | grep '^BORG_' | sort > "$dir/env"
+if [ "$BORG_REPO" = missing ]; then
+    echo "Repository $BORG_REPO does not exist." >&2
+    exit 2
+fi
+cat > "$dir/archive.tar"
+"#;
+
+    fn fake_borg(dir: &Path) -> Result<PathBuf> {
+        let borg = dir.join("borg");
+        std::fs::write(&borg, FAKE_BORG)?;
+        std::fs::set_permissions(&borg, std::fs::Permissions::from_mode(0o755))?;
+        Ok(borg)
+    }
+
+    #[tokio::test]
+    async fn imports_a_tar_of_the_stage() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("disk-drive-borg-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir)?;
+        let borg = fake_borg(&dir)?;
+
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/docs").await?;
+        src.write("/docs/a.txt", "hello").await?;
+        src.set_permissions("/docs/a.txt", MemPermissions::from_mode(0o640))
+            .await?;
+        src.symlink(PathBuf::from("docs/a.txt"), PathBuf::from("/link"))
+            .await?;
+
+        let dest = BorgFloppyDisk::new(
+            BorgSink::new("/srv/borg")
+                .borg_program(&borg)
+                .passphrase("secret")
+                .archive("nightly")
+                .compression("zstd,3"),
+        );
+        let copied = DiskDrive::copy_between(&src, &dest).await;
+
+        let check = || -> Result<()> {
+            copied?;
+            assert_eq!(
+                std::fs::read_to_string(dir.join("args"))?,
+                "import-tar\n--compression\nzstd,3\n::nightly\n-\n"
+            );
+            assert_eq!(
+                std::fs::read_to_string(dir.join("env"))?,
+                "BORG_PASSPHRASE=secret\n\
+                 BORG_RELOCATED_REPO_ACCESS_IS_OK=no\n\
+                 BORG_REPO=/srv/borg\n\
+                 BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK=no\n"
+            );
+
+            let out = dir.join("out");
+            std::fs::create_dir(&out)?;
+            let status = std::process::Command::new("tar")
+                .arg("-xpf")
+                .arg(dir.join("archive.tar"))
+                .arg("-C")
+                .arg(&out)
+                .status()?;
+            assert!(status.success());
+            assert_eq!(std::fs::read(out.join("docs/a.txt"))?, b"hello");
+            let mode = std::fs::metadata(out.join("docs/a.txt"))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o640);
+            assert_eq!(
+                std::fs::read_link(out.join("link"))?,
+                PathBuf::from("docs/a.txt")
+            );
+            Ok(())
+        };
+        let result = check();
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn reports_borg_errors() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("disk-drive-borg-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir)?;
+        let borg = fake_borg(&dir)?;
+
+        let src = MemFloppyDisk::new();
+        src.write("/f", "x").await?;
+        let dest = BorgFloppyDisk::new(BorgSink::new("missing").borg_program(&borg));
+        let copied = DiskDrive::copy_between(&src, &dest).await;
+        std::fs::remove_dir_all(&dir)?;
+
+        let error = copied.unwrap_err().to_string();
+        assert!(error.starts_with("borg import-tar failed"));
+        assert!(error.ends_with("Repository missing does not exist."));
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! restic's content-defined chunker: a Rabin fingerprint over a sliding
+//! 64-byte window, cutting wherever the low 20 bits of the fingerprint are
+//! zero. Chunks are between 512 KiB and 8 MiB, and the polynomial comes
+//! from the repository's config, so the same data is cut the same way as
+//! restic would cut it.
+
+use eyre::{eyre, Result};
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const WINDOW_SIZE: usize = 64;
+const MIN_SIZE: usize = 512 * 1024;
+const MAX_SIZE: usize = 8 * 1024 * 1024;
+const SPLIT_MASK: u64 = (1 << 20) - 1;
+const READ_SIZE: usize = 512 * 1024;
+
+/// A polynomial over GF(2), one bit per coefficient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pol(pub(crate) u64);
+
+impl Pol {
+    /// Picks polynomials of degree 53 until one is irreducible, the way
+    /// `restic init` does.
+    pub(crate) fn random() -> Result<Self> {
+        for _ in 0..1_000_000 {
+            let candidate = (rand::rngs::OsRng.next_u64() & ((1 << 54) - 1)) | (1 << 53) | 1;
+            let pol = Pol(candidate);
+            if pol.irreducible() {
+                return Ok(pol);
+            }
+        }
+        Err(eyre!("couldn't find an irreducible polynomial"))
+    }
+
+    /// The degree, or -1 for the zero polynomial.
+    fn deg(self) -> i32 {
+        63 - self.0.leading_zeros() as i32
+    }
+
+    fn modulo(self, d: Pol) -> Pol {
+        let mut x = self.0;
+        while Pol(x).deg() >= d.deg() {
+            x ^= d.0 << (Pol(x).deg() - d.deg());
+        }
+        Pol(x)
+    }
+
+    /// `self * other mod m`. Both factors have to be reduced already.
+    fn mul_mod(self, other: Pol, m: Pol) -> Pol {
+        let mut product = 0u128;
+        for i in 0..64 {
+            if other.0 & (1 << i) != 0 {
+                product ^= u128::from(self.0) << i;
+            }
+        }
+
+        let deg = m.deg() as u32;
+        for i in (deg..128).rev() {
+            if product & (1 << i) != 0 {
+                product ^= u128::from(m.0) << (i - deg);
+            }
+        }
+        Pol(product as u64)
+    }
+
+    fn gcd(self, other: Pol) -> Pol {
+        let (mut a, mut b) = (self, other);
+        while b.0 != 0 {
+            (a, b) = (b, a.modulo(b));
+        }
+        a
+    }
+
+    /// Ben-Or's irreducibility test: `self` is irreducible if it shares no
+    /// factor with `x^(2^i) - x` for any `i` up to half its degree.
+    pub(crate) fn irreducible(self) -> bool {
+        let mut power = Pol(2);
+        for _ in 1..=self.deg() / 2 {
+            // x^(2^i), by squaring x^(2^(i-1))
+            power = power.mul_mod(power, self);
+            if self.gcd(Pol(power.0 ^ 2).modulo(self)) != Pol(1) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The lookup tables for one polynomial. Build it once per repository and
+/// start a [`Chunks`] for each file.
+pub(crate) struct Chunker {
+    out: [u64; 256],
+    reduce: [u64; 256],
+    shift: i32,
+}
+
+impl Chunker {
+    pub(crate) fn new(pol: Pol) -> Self {
+        let append_byte = |hash: u64, b: u8| Pol((hash << 8) | u64::from(b)).modulo(pol).0;
+
+        // out[b] is the fingerprint of b followed by a window of zeroes, so
+        // adding it slides b out of the window
+        let mut out = [0; 256];
+        for (b, out) in out.iter_mut().enumerate() {
+            let mut hash = append_byte(0, b as u8);
+            for _ in 0..WINDOW_SIZE - 1 {
+                hash = append_byte(hash, 0);
+            }
+            *out = hash;
+        }
+
+        // reduce[b] reduces the 8 bits about to be shifted past the
+        // polynomial's degree, and cancels them out in the same xor
+        let k = pol.deg();
+        let mut reduce = [0; 256];
+        for (b, reduce) in reduce.iter_mut().enumerate() {
+            *reduce = Pol((b as u64) << k).modulo(pol).0 | ((b as u64) << k);
+        }
+
+        Self {
+            out,
+            reduce,
+            shift: k - 8,
+        }
+    }
+
+    pub(crate) fn chunks<R: AsyncRead + Unpin>(&self, reader: R) -> Chunks<'_, R> {
+        let mut chunks = Chunks {
+            chunker: self,
+            reader,
+            buffer: vec![0; READ_SIZE],
+            pos: 0,
+            filled: 0,
+            window: [0; WINDOW_SIZE],
+            wpos: 0,
+            digest: 0,
+            pre: 0,
+            chunk: vec![],
+        };
+        chunks.reset();
+        chunks
+    }
+}
+
+pub(crate) struct Chunks<'a, R> {
+    chunker: &'a Chunker,
+    reader: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    window: [u8; WINDOW_SIZE],
+    wpos: usize,
+    digest: u64,
+    /// Bytes left to take before the fingerprint starts mattering.
+    pre: usize,
+    chunk: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Chunks<'_, R> {
+    fn reset(&mut self) {
+        self.window = [0; WINDOW_SIZE];
+        self.wpos = 0;
+        self.digest = 0;
+        self.slide(1);
+        // nothing before MIN_SIZE can be a cut point, so those bytes skip
+        // the window, bar the last window's worth that primes it
+        self.pre = MIN_SIZE - WINDOW_SIZE;
+    }
+
+    fn slide(&mut self, b: u8) {
+        let out = self.window[self.wpos];
+        self.window[self.wpos] = b;
+        self.digest ^= self.chunker.out[out as usize];
+        self.wpos = (self.wpos + 1) % WINDOW_SIZE;
+
+        let index = (self.digest >> self.chunker.shift) as u8;
+        self.digest = (self.digest << 8) | u64::from(b);
+        self.digest ^= self.chunker.reduce[index as usize];
+    }
+
+    /// The next chunk, or `None` once the reader is exhausted.
+    pub(crate) async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if self.pos == self.filled {
+                self.filled = self.reader.read(&mut self.buffer).await?;
+                self.pos = 0;
+                if self.filled == 0 {
+                    if self.chunk.is_empty() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(std::mem::take(&mut self.chunk)));
+                }
+            }
+
+            if self.pre > 0 {
+                let take = self.pre.min(self.filled - self.pos);
+                self.chunk
+                    .extend_from_slice(&self.buffer[self.pos..self.pos + take]);
+                self.pos += take;
+                self.pre -= take;
+                continue;
+            }
+
+            let start = self.pos;
+            while self.pos < self.filled {
+                let b = self.buffer[self.pos];
+                self.slide(b);
+                self.pos += 1;
+
+                let len = self.chunk.len() + self.pos - start;
+                if len >= MIN_SIZE && (self.digest & SPLIT_MASK == 0 || len >= MAX_SIZE) {
+                    self.chunk.extend_from_slice(&self.buffer[start..self.pos]);
+                    self.reset();
+                    return Ok(Some(std::mem::take(&mut self.chunk)));
+                }
+            }
+            self.chunk.extend_from_slice(&self.buffer[start..self.pos]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// The polynomial restic's chunker tests use.
+    const POL: Pol = Pol(0x3DA3358B4DC173);
+
+    fn data(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        rand::rngs::StdRng::seed_from_u64(23).fill(&mut data[..]);
+        data
+    }
+
+    async fn cut(chunker: &Chunker, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut chunks = chunker.chunks(data);
+        let mut out = vec![];
+        while let Some(chunk) = chunks.next().await? {
+            out.push(chunk);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn finds_irreducible_polynomials() -> Result<()> {
+        assert!(POL.irreducible());
+        // (x^2 + x + 1)(x^3 + x + 1)
+        assert!(!Pol(0b110001).irreducible());
+        assert!(!Pol(0x3DA3358B4DC172).irreducible());
+
+        let random = Pol::random()?;
+        assert_eq!(random.deg(), 53);
+        assert!(random.irreducible());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cuts_by_content() -> Result<()> {
+        let chunker = Chunker::new(POL);
+        let data = data(12 * 1024 * 1024);
+        let chunks = cut(&chunker, &data).await?;
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!((MIN_SIZE..=MAX_SIZE).contains(&chunk.len()));
+        }
+
+        // the same data is cut the same way after something's put in front
+        // of it, once the chunker has found its way back to a cut point
+        let shifted = cut(&chunker, &[&[1, 2, 3][..], &data].concat()).await?;
+        assert_eq!(shifted.last(), chunks.last());
+        assert!(shifted[1..].iter().any(|chunk| chunks.contains(chunk)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cuts_small_inputs_and_zeroes() -> Result<()> {
+        let chunker = Chunker::new(POL);
+        assert!(cut(&chunker, &[]).await?.is_empty());
+        assert_eq!(cut(&chunker, b"small").await?, [b"small".to_vec()]);
+
+        // a window of zeroes fingerprints to zero, so zeroes are cut as
+        // soon as they're allowed to be
+        let zeroes = vec![0; 2 * MIN_SIZE + 10];
+        let lens: Vec<_> = cut(&chunker, &zeroes).await?.iter().map(Vec::len).collect();
+        assert_eq!(lens, [MIN_SIZE, MIN_SIZE, 10]);
+        Ok(())
+    }
+}
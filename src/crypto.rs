@@ -0,0 +1,155 @@
+//! The primitives restic's repository format is built from, put together
+//! from the RustCrypto crates: AES-256 in counter mode, Poly1305-AES, and
+//! scrypt.
+
+use aes::cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use aes::{Aes128, Aes256};
+use eyre::{eyre, Result};
+use poly1305::Poly1305;
+
+/// Encrypts or decrypts `data` in place, with the whole IV as a big-endian
+/// 128-bit counter.
+pub(crate) fn aes256_ctr(key: &[u8; 32], iv: &[u8; 16], data: &mut [u8]) {
+    ctr::Ctr128BE::<Aes256>::new(key.into(), iv.into()).apply_keystream(data);
+}
+
+/// Poly1305-AES: `k` encrypts the nonce into Poly1305's `s`.
+pub(crate) fn poly1305_aes(
+    k: &[u8; 16],
+    r: &[u8; 16],
+    nonce: &[u8; 16],
+    message: &[u8],
+) -> [u8; 16] {
+    let mut s = (*nonce).into();
+    Aes128::new(k.into()).encrypt_block(&mut s);
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(r);
+    key[16..].copy_from_slice(&s);
+    Poly1305::new(&key.into()).compute_unpadded(message).into()
+}
+
+/// Compares MACs without stopping at the first difference.
+pub(crate) fn verify(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// scrypt, from RFC 7914. `n` has to be a power of two, and the parameters
+/// are checked against `max_memory` before anything is allocated, since
+/// they can come from a file the server handed over.
+pub(crate) fn scrypt(
+    password: &[u8],
+    salt: &[u8],
+    n: u64,
+    r: u32,
+    p: u32,
+    len: usize,
+    max_memory: u64,
+) -> Result<Vec<u8>> {
+    let invalid = || eyre!("invalid scrypt parameters N={n} r={r} p={p}");
+    if n < 2 || !n.is_power_of_two() {
+        return Err(invalid());
+    }
+    if (n as u128) * 128 * u128::from(r) > u128::from(max_memory) {
+        return Err(eyre!(
+            "scrypt parameters N={n} r={r} need more than {max_memory} bytes"
+        ));
+    }
+
+    let params = scrypt::Params::new(n.trailing_zeros() as u8, r, p, len).map_err(|_| invalid())?;
+    let mut out = vec![0; len];
+    scrypt::scrypt(password, salt, &params, &mut out).map_err(|_| invalid())?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ctr_matches_sp_800_38a() {
+        // F.5.5, CTR-AES256.Encrypt
+        let key = unhex("603deb1015ca71be2b73aef0857d7781 1f352c073b6108d72d9810a30914dff4")
+            .try_into()
+            .unwrap();
+        let iv = unhex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")
+            .try_into()
+            .unwrap();
+        let mut data = unhex(
+            "6bc1bee22e409f96e93d7e117393172a ae2d8a571e03ac9c9eb76fac45af8e51
+             30c81c46a35ce411e5fbc1191a0a52ef f69f2445df4f9b17ad2b417be66c3710",
+        );
+        aes256_ctr(&key, &iv, &mut data);
+        assert_eq!(
+            data,
+            unhex(
+                "601ec313775789a5b7a7f504bbf3d228 f443e3ca4d62b59aca84e990cacaf5c5
+                 2b0930daa23de94ce87017ba2d84988d dfc9c58db67aada613c2dd08457941a6"
+            )
+        );
+
+        // the counter carries across the whole 128 bits
+        let mut data = [0; 32];
+        aes256_ctr(&key, &[0xff; 16], &mut data);
+        let aes = Aes256::new(&key.into());
+        let mut first = [0xff; 16].into();
+        aes.encrypt_block(&mut first);
+        let mut second = [0; 16].into();
+        aes.encrypt_block(&mut second);
+        assert_eq!(data, [first, second].concat()[..]);
+    }
+
+    #[test]
+    fn poly1305_aes_matches_the_paper() {
+        // appendix B, the first example
+        let tag = poly1305_aes(
+            &unhex("ec074c835580741701425b623235add6")
+                .try_into()
+                .unwrap(),
+            &unhex("851fc40c3467ac0be05cc20404f3f700")
+                .try_into()
+                .unwrap(),
+            &unhex("fb447350c4e868c52ac3275cf9d4327e")
+                .try_into()
+                .unwrap(),
+            &[0xf3, 0xf6],
+        );
+        assert_eq!(tag.to_vec(), unhex("f4c633c3044fc145f84f335cb81953de"));
+    }
+
+    #[test]
+    fn scrypt_matches_rfc_7914() -> Result<()> {
+        // section 12, but the last vector takes too long
+        assert_eq!(
+            scrypt(b"", b"", 16, 1, 1, 64, 1 << 30)?,
+            unhex(
+                "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442
+                 fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+            )
+        );
+        assert_eq!(
+            scrypt(b"password", b"NaCl", 1024, 8, 16, 64, 1 << 30)?,
+            unhex(
+                "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162
+                 2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+            )
+        );
+
+        assert!(scrypt(b"", b"", 1000, 1, 1, 64, 1 << 30).is_err());
+        assert!(scrypt(b"", b"", 16, 0, 1, 64, 1 << 30).is_err());
+        assert!(scrypt(b"", b"", 1 << 20, 8, 1, 64, 1 << 20).is_err());
+        Ok(())
+    }
+}
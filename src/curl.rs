@@ -0,0 +1,191 @@
+//! Requests made through the system `curl`, which already knows how to speak
+//! TLS and HTTP to everything the remote backends talk to. The backends that
+//! use it need `curl` on the `PATH` at runtime.
+//!
+//! Everything is handed to curl as a config file on stdin, so credentials
+//! never show up in the process list, and nothing is written to disk.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use eyre::{eyre, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::trace;
+
+#[derive(Debug)]
+pub(crate) struct Curl {
+    url: String,
+    config: Vec<(&'static str, String)>,
+    /// A local file sent with `--upload-file`, which is a `PUT` unless
+    /// `method` says otherwise.
+    upload: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Curl {
+    pub(crate) fn new<S: Into<String>>(url: S) -> Self {
+        Self {
+            url: url.into(),
+            config: vec![],
+            upload: None,
+        }
+    }
+
+    #[cfg(feature = "restic")]
+    pub(crate) fn method(self, method: &str) -> Self {
+        self.option("request", method)
+    }
+
+    pub(crate) fn header<S: AsRef<str>>(self, name: &str, value: S) -> Self {
+        let header = format!("{name}: {}", value.as_ref());
+        self.option("header", header)
+    }
+
+    /// Any other long option, without its leading `--`.
+    pub(crate) fn option<S: Into<String>>(mut self, name: &'static str, value: S) -> Self {
+        self.config.push((name, value.into()));
+        self
+    }
+
+    pub(crate) fn upload<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.upload = Some(path.into());
+        self
+    }
+
+    pub(crate) async fn send(self) -> Result<Response> {
+        let mut config = String::new();
+        push_option(&mut config, "url", &self.url);
+        push_option(&mut config, "write-out", "\n%{response_code}");
+        for (name, value) in &self.config {
+            push_option(&mut config, name, value);
+        }
+        if let Some(path) = &self.upload {
+            push_option(&mut config, "upload-file", &path.display().to_string());
+        }
+
+        let url = redacted(&self.url);
+        trace!("curl {url}");
+        let mut child = Command::new("curl")
+            .args(["--disable", "--silent", "--show-error", "--config", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(config.as_bytes()).await?;
+        }
+        let output = child.wait_with_output().await?;
+
+        if !output.status.success() {
+            return Err(eyre!(
+                "curl {url} failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let mut body = output.stdout;
+        let split = body
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .ok_or_else(|| eyre!("curl {url} returned no response code"))?;
+        let status = std::str::from_utf8(&body[split + 1..])?.trim().parse()?;
+        body.truncate(split);
+
+        Ok(Response { status, body })
+    }
+}
+
+/// `url` with any credentials in it blanked out, for logs and errors.
+fn redacted(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    match rest[..authority].rfind('@') {
+        Some(at) => format!("{scheme}://***@{}", &rest[at + 1..]),
+        None => url.to_string(),
+    }
+}
+
+fn push_option(config: &mut String, name: &str, value: &str) {
+    config.push_str(name);
+    config.push_str(" = \"");
+    for c in value.chars() {
+        match c {
+            '"' => config.push_str("\\\""),
+            '\\' => config.push_str("\\\\"),
+            '\n' => config.push_str("\\n"),
+            '\r' => config.push_str("\\r"),
+            '\t' => config.push_str("\\t"),
+            c => config.push(c),
+        }
+    }
+    config.push_str("\"\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::serve;
+
+    #[tokio::test]
+    async fn quotes_options() -> Result<()> {
+        let (url, requests) = serve(|_| (201, b"created".to_vec())).await;
+        // --data-raw, unlike --data-binary, doesn't read a file for a
+        // leading @
+        let data = "@not-a-file \"quoted\" back\\slash\nnew line\ttab";
+        let response = Curl::new(format!("{url}/data"))
+            .header("X-Test", "yes")
+            .option("data-raw", data)
+            .send()
+            .await?;
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"created");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/data");
+        assert_eq!(requests[0].header("x-test"), Some("yes"));
+        assert_eq!(requests[0].body, data.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn redacts_credentials() {
+        assert_eq!(
+            redacted("https://user:p@ss@example.com:8000/repo/?a=b@c"),
+            "https://***@example.com:8000/repo/?a=b@c"
+        );
+        assert_eq!(redacted("http://example.com/a@b"), "http://example.com/a@b");
+        assert_eq!(redacted("not a url"), "not a url");
+    }
+
+    #[tokio::test]
+    async fn uploads_files_from_disk() -> Result<()> {
+        let (url, requests) = serve(|_| (200, vec![])).await;
+        let path =
+            std::env::temp_dir().join(format!("disk-drive-curl-{:016x}", rand::random::<u64>()));
+        let contents: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        tokio::fs::write(&path, &contents).await?;
+        let response = Curl::new(format!("{url}/upload"))
+            .upload(&path)
+            .send()
+            .await;
+        tokio::fs::remove_file(&path).await?;
+        assert_eq!(response?.status, 200);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(requests[0].body, contents);
+        Ok(())
+    }
+}
@@ -0,0 +1,687 @@
+//! Writes Duplicati 2 backup sets into a local directory. Each copy becomes
+//! one new backup version, laid out the way Duplicati's "Restore from backup
+//! files" reads them. The tests read sets back with their own reader; they
+//! haven't been checked against Duplicati itself.
+//!
+//! Files are split into fixed-size blocks, and each distinct block is
+//! stored once no matter how many files, or how many places in one file, it
+//! turns up in. Blocks are packed into `dblock` volumes, each with a
+//! `dindex` volume listing what's in it, and the version itself is a
+//! `dlist` volume with the file list. Volumes are unencrypted zips.
+//!
+//! Only blocks from the copy being written are deduplicated against; blocks
+//! already in the directory from earlier copies are stored again.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::{eyre, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::base64;
+use crate::json::Json;
+use crate::sha256::{sha256, Sha256, HASH_LEN};
+use crate::staged::{Sink, Stage, StagedEntry, StagedFloppyDisk, StagedKind};
+use crate::time::civil;
+use crate::zip::ZipWriter;
+use crate::CopySummary;
+
+pub type DuplicatiFloppyDisk = StagedFloppyDisk<DuplicatiSink>;
+
+/// Zips without zip64 hold at most `u16::MAX` entries. A dblock volume
+/// holds its blocks and a manifest, and its dindex a manifest, the volume's
+/// entry, and a list for each of those blocks that's a blocklist.
+const MAX_VOLUME_BLOCKS: usize = u16::MAX as usize - 2;
+
+#[derive(Debug)]
+pub struct DuplicatiSink {
+    dir: PathBuf,
+    prefix: String,
+    block_size: usize,
+    volume_size: usize,
+}
+
+impl DuplicatiSink {
+    /// `dir` is where the volumes are written. It's created if it doesn't
+    /// exist.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: "duplicati".into(),
+            block_size: 100 * 1024,
+            volume_size: 50 * 1024 * 1024,
+        }
+    }
+
+    /// The file name prefix shared by every volume in the backup set.
+    /// Defaults to Duplicati's own default of `duplicati`.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// How big each block is. Defaults to 100 KiB. Every version in a backup
+    /// set has to use the same block size.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size.max(HASH_LEN);
+        self
+    }
+
+    /// Roughly how big each dblock volume gets before a new one is started.
+    /// Defaults to 50 MiB, and can't go past 4 GiB.
+    pub fn volume_size(mut self, volume_size: usize) -> Self {
+        self.volume_size = volume_size.min(u32::MAX as usize);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for DuplicatiSink {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let created = SystemTime::now();
+        let mut volumes = Volumes::new(self, created);
+        let mut filelist = vec![];
+
+        for entry in stage.entries() {
+            let mut path = entry.path.to_string_lossy().to_string();
+            let metadata = metadata(entry).to_string().into_bytes();
+            let metahash = base64::encode(&volumes.add_block(&metadata).await?);
+
+            let mut fields: Vec<(&str, Json)> = vec![];
+            match &entry.kind {
+                StagedKind::Dir => {
+                    // folders are told apart from files by the trailing
+                    // separator
+                    if !path.ends_with('/') {
+                        path.push('/');
+                    }
+                    fields.push(("type", "Folder".into()));
+                    fields.push(("path", path.into()));
+                }
+                StagedKind::Symlink { .. } => {
+                    fields.push(("type", "Symlink".into()));
+                    fields.push(("path", path.into()));
+                }
+                StagedKind::File { .. } => {
+                    let mut file = stage.open(entry).await?;
+                    let mut block = vec![0; self.block_size];
+                    let mut file_hash = Sha256::new();
+                    let mut size = 0u64;
+                    let mut hashes = vec![];
+                    loop {
+                        let mut filled = 0;
+                        while filled < block.len() {
+                            match file.read(&mut block[filled..]).await? {
+                                0 => break,
+                                read => filled += read,
+                            }
+                        }
+                        // empty files still get one (empty) block, same as
+                        // Duplicati does
+                        if filled > 0 || hashes.is_empty() {
+                            file_hash.update(&block[..filled]);
+                            size += filled as u64;
+                            hashes.push(volumes.add_block(&block[..filled]).await?);
+                        }
+                        if filled < block.len() {
+                            break;
+                        }
+                    }
+
+                    fields.push(("type", "File".into()));
+                    fields.push(("path", path.into()));
+                    fields.push(("hash", base64::encode(&file_hash.finish()).into()));
+                    fields.push(("size", size.into()));
+                    fields.push(("time", serialize_date(entry.modified).into()));
+
+                    // a single block's hash is the file's hash, so only
+                    // bigger files need their blocks listed
+                    if hashes.len() > 1 {
+                        let mut blocklists = vec![];
+                        for list in hashes.chunks(self.block_size / HASH_LEN) {
+                            let hash = volumes.add_blocklist(&list.concat()).await?;
+                            blocklists.push(base64::encode(&hash).into());
+                        }
+                        fields.push(("blocklists", blocklists.into()));
+                    }
+                }
+            }
+            fields.push(("metahash", metahash.into()));
+            fields.push(("metasize", (metadata.len() as u64).into()));
+            filelist.push(Json::object(fields));
+        }
+
+        let dlist = volumes.finish(Json::Array(filelist)).await?;
+        summary.snapshot_id = Some(dlist);
+
+        Ok(())
+    }
+}
+
+/// Keeps track of the blocks written so far, and of the dblock volume that
+/// new ones are going into.
+struct Volumes<'a> {
+    sink: &'a DuplicatiSink,
+    created: SystemTime,
+    seen: HashSet<[u8; HASH_LEN]>,
+    dblock: ZipWriter,
+    blocks: Vec<(String, usize)>,
+    blocklists: Vec<(String, Vec<u8>)>,
+}
+
+impl<'a> Volumes<'a> {
+    fn new(sink: &'a DuplicatiSink, created: SystemTime) -> Self {
+        Self {
+            sink,
+            created,
+            seen: HashSet::new(),
+            dblock: ZipWriter::new(dos_date(created)),
+            blocks: vec![],
+            blocklists: vec![],
+        }
+    }
+
+    /// Stores a block unless it's already been stored, and returns its
+    /// hash.
+    async fn add_block(&mut self, data: &[u8]) -> Result<[u8; HASH_LEN]> {
+        let hash = sha256(data);
+        if self.seen.insert(hash) {
+            let full = self.dblock.len() + data.len() > self.sink.volume_size
+                || self.blocks.len() >= MAX_VOLUME_BLOCKS;
+            if !self.blocks.is_empty() && full {
+                self.close_volume().await?;
+            }
+            let encoded = base64::encode(&hash);
+            self.dblock.add(&url_safe(&encoded), data)?;
+            self.blocks.push((encoded, data.len()));
+        }
+        Ok(hash)
+    }
+
+    /// Stores a list of block hashes, which is itself a block, and also
+    /// records it in the index so restores can find it without opening
+    /// every dblock volume.
+    async fn add_blocklist(&mut self, data: &[u8]) -> Result<[u8; HASH_LEN]> {
+        let new = !self.seen.contains(&sha256(data));
+        let hash = self.add_block(data).await?;
+        if new {
+            self.blocklists.push((base64::encode(&hash), data.to_vec()));
+        }
+        Ok(hash)
+    }
+
+    /// Writes out the current dblock volume and its dindex volume.
+    async fn close_volume(&mut self) -> Result<()> {
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        let dblock_name = format!("{}-b{}.dblock.zip", self.sink.prefix, guid());
+        let mut dblock =
+            std::mem::replace(&mut self.dblock, ZipWriter::new(dos_date(self.created)));
+        dblock.add("manifest", &self.manifest())?;
+        let dblock = dblock.finish()?;
+
+        let mut dindex = ZipWriter::new(dos_date(self.created));
+        dindex.add("manifest", &self.manifest())?;
+        let blocks = self
+            .blocks
+            .drain(..)
+            .map(|(hash, size)| {
+                Json::object([("hash", hash.into()), ("size", (size as u64).into())])
+            })
+            .collect::<Vec<_>>();
+        let volume = Json::object([
+            ("blocks", blocks.into()),
+            ("volumehash", base64::encode(&sha256(&dblock)).into()),
+            ("volumesize", (dblock.len() as u64).into()),
+        ]);
+        dindex.add(&format!("vol/{dblock_name}"), volume.to_string().as_bytes())?;
+        for (hash, list) in self.blocklists.drain(..) {
+            dindex.add(&format!("list/{}", url_safe(&hash)), &list)?;
+        }
+        let dindex = dindex.finish()?;
+
+        debug!("duplicati writing {dblock_name}");
+        tokio::fs::write(self.sink.dir.join(&dblock_name), dblock).await?;
+        let dindex_name = format!("{}-i{}.dindex.zip", self.sink.prefix, guid());
+        tokio::fs::write(self.sink.dir.join(dindex_name), dindex).await?;
+
+        Ok(())
+    }
+
+    /// Writes out the last volume, then the dlist that makes the version
+    /// real, and returns the dlist's name. The dlist goes last so that a
+    /// copy that fails partway leaves no version pointing at missing
+    /// volumes.
+    async fn finish(mut self, filelist: Json) -> Result<String> {
+        self.close_volume().await?;
+
+        let mut dlist = ZipWriter::new(dos_date(self.created));
+        dlist.add("manifest", &self.manifest())?;
+        dlist.add("filelist.json", filelist.to_string().as_bytes())?;
+        dlist.add(
+            "fileset",
+            Json::object([("IsFullBackup", true.into())])
+                .to_string()
+                .as_bytes(),
+        )?;
+        let dlist = dlist.finish()?;
+
+        let name = format!(
+            "{}-{}.dlist.zip",
+            self.sink.prefix,
+            serialize_date(self.created)
+        );
+        debug!("duplicati writing {name}");
+        // versions are named by the second they were made in, so don't
+        // clobber one that happened to land in the same second
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.sink.dir.join(&name))
+            .await
+            .map_err(|e| eyre!("couldn't create {name}: {e}"))?;
+        file.write_all(&dlist).await?;
+        file.flush().await?;
+
+        Ok(name)
+    }
+
+    fn manifest(&self) -> Vec<u8> {
+        Json::object([
+            ("Version", 2u64.into()),
+            ("Created", serialize_date(self.created).into()),
+            ("Encoding", "utf8".into()),
+            ("Blocksize", (self.sink.block_size as u64).into()),
+            ("BlockHash", "SHA256".into()),
+            ("FileHash", "SHA256".into()),
+            (
+                "AppVersion",
+                concat!("disk-drive ", env!("CARGO_PKG_VERSION")).into(),
+            ),
+        ])
+        .to_string()
+        .into_bytes()
+    }
+}
+
+/// The metadata Duplicati keeps for each entry, stored as a block of its
+/// own.
+fn metadata(entry: &StagedEntry) -> Json {
+    let ticks = ticks(entry.modified).to_string();
+    let attributes = match entry.kind {
+        StagedKind::Dir => "Directory",
+        StagedKind::File { .. } => "Normal",
+        StagedKind::Symlink { .. } => "ReparsePoint",
+    };
+    let mut fields: Vec<(&str, Json)> = vec![
+        ("CoreAttributes", attributes.into()),
+        ("CoreLastWritetime", ticks.clone().into()),
+        ("CoreCreatetime", ticks.into()),
+        (
+            "unix:uid-gid-perm",
+            format!("{}-{}-{}", entry.uid, entry.gid, entry.mode).into(),
+        ),
+    ];
+    if let StagedKind::Symlink { target } = &entry.kind {
+        fields.push((
+            "CoreSymlinkTarget",
+            target.to_string_lossy().to_string().into(),
+        ));
+    }
+    Json::object(fields)
+}
+
+/// Volumes other than dlists are named with a random GUID, as 32 hex
+/// digits.
+fn guid() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// .NET ticks: 100ns intervals since 0001-01-01 UTC.
+fn ticks(time: SystemTime) -> u64 {
+    const UNIX_EPOCH_TICKS: u64 = 621_355_968_000_000_000;
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH_TICKS + since.as_secs() * 10_000_000 + since.subsec_nanos() as u64 / 100
+}
+
+/// Duplicati's `yyyyMMddTHHmmssZ` timestamps.
+fn serialize_date(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil(time);
+    format!("{year:04}{month:02}{day:02}T{hour:02}{minute:02}{second:02}Z")
+}
+
+/// MS-DOS `(time, date)`, as zip entries want them.
+fn dos_date(time: SystemTime) -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = civil(time);
+    let time = ((hour << 11) | (minute << 5) | (second / 2)) as u16;
+    let date = (((year.clamp(1980, 2107) - 1980) << 9) | (month << 5) | day) as u16;
+    (time, date)
+}
+
+/// Blocks are named in volumes by their hash, with the two characters that
+/// aren't safe in file names swapped out.
+fn url_safe(hash: &str) -> String {
+    hash.replace('+', "-").replace('/', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use floppy_disk::mem::MemPermissions;
+    use floppy_disk::prelude::*;
+
+    use super::*;
+    use crate::zip;
+    use crate::DiskDrive;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// A backup set read back from its directory.
+    struct Set {
+        dlists: Vec<(String, Vec<u8>)>,
+        /// Every block in every dblock volume, by url-safe hash.
+        blocks: BTreeMap<String, Vec<u8>>,
+        /// Every blocklist in every dindex volume, by url-safe hash.
+        lists: BTreeMap<String, Vec<u8>>,
+        dblocks: usize,
+    }
+
+    impl Set {
+        fn read(dir: &Path) -> Result<Self> {
+            let mut volumes = BTreeMap::new();
+            for file in std::fs::read_dir(dir)? {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().to_string();
+                volumes.insert(name, std::fs::read(file.path())?);
+            }
+
+            let mut set = Set {
+                dlists: vec![],
+                blocks: BTreeMap::new(),
+                lists: BTreeMap::new(),
+                dblocks: 0,
+            };
+            let mut indexed = BTreeMap::new();
+            for (name, volume) in &volumes {
+                let (manifest, entries): (Vec<_>, Vec<_>) = zip::read(volume)?
+                    .into_iter()
+                    .partition(|(name, _)| name == "manifest");
+                let manifest = Json::parse(&manifest[0].1)?;
+                assert_eq!(
+                    manifest.get("Blocksize").and_then(Json::as_u64),
+                    Some(BLOCK_SIZE as u64)
+                );
+                assert_eq!(
+                    manifest.get("BlockHash").and_then(Json::as_str),
+                    Some("SHA256")
+                );
+
+                if name.ends_with(".dlist.zip") {
+                    set.dlists.push((name.clone(), volume.clone()));
+                } else if name.ends_with(".dblock.zip") {
+                    set.dblocks += 1;
+                    for (hash, data) in entries {
+                        assert_eq!(hash, url_safe(&base64::encode(&sha256(&data))));
+                        // each block is only stored once across the whole set
+                        assert!(set.blocks.insert(hash, data).is_none());
+                    }
+                } else {
+                    assert!(name.ends_with(".dindex.zip"), "stray file {name}");
+                    for (entry, data) in entries {
+                        if let Some(dblock) = entry.strip_prefix("vol/") {
+                            indexed.insert(dblock.to_string(), Json::parse(data)?);
+                        } else {
+                            let hash = entry.strip_prefix("list/").unwrap();
+                            set.lists.insert(hash.to_string(), data);
+                        }
+                    }
+                }
+            }
+
+            // every dblock has exactly one dindex, and it has the dblock right
+            assert_eq!(indexed.len(), set.dblocks);
+            for (dblock, index) in &indexed {
+                let volume = &volumes[dblock];
+                assert_eq!(
+                    index.get("volumehash").and_then(Json::as_str),
+                    Some(base64::encode(&sha256(volume)).as_str())
+                );
+                assert_eq!(
+                    index.get("volumesize").and_then(Json::as_u64),
+                    Some(volume.len() as u64)
+                );
+                let blocks = index.get("blocks").and_then(Json::as_array).unwrap();
+                let stored: Vec<_> = zip::read(volume)?
+                    .into_iter()
+                    .filter(|(name, _)| name != "manifest")
+                    .collect();
+                assert_eq!(blocks.len(), stored.len());
+                for (block, (name, data)) in blocks.iter().zip(&stored) {
+                    let hash = block.get("hash").and_then(Json::as_str).unwrap();
+                    assert_eq!(&url_safe(hash), name);
+                    assert_eq!(
+                        block.get("size").and_then(Json::as_u64),
+                        Some(data.len() as u64)
+                    );
+                }
+            }
+            for (hash, list) in &set.lists {
+                assert_eq!(set.blocks.get(hash), Some(list));
+            }
+            Ok(set)
+        }
+
+        fn block(&self, hash: &str) -> &[u8] {
+            &self.blocks[&url_safe(hash)]
+        }
+
+        /// The filelist of the newest version, by path.
+        fn filelist(&self) -> Result<BTreeMap<String, Json>> {
+            let (_, dlist) = self.dlists.last().unwrap();
+            let entries = zip::read(dlist)?;
+            let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, ["manifest", "filelist.json", "fileset"]);
+            let filelist = Json::parse(&entries[1].1)?;
+            Ok(filelist
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| {
+                    let path = entry.get("path").and_then(Json::as_str).unwrap();
+                    (path.to_string(), entry.clone())
+                })
+                .collect())
+        }
+
+        /// Puts a file back together the way a restore does.
+        fn contents(&self, entry: &Json) -> Vec<u8> {
+            let hash = entry.get("hash").and_then(Json::as_str).unwrap();
+            let contents = match entry.get("blocklists").and_then(Json::as_array) {
+                None => self.block(hash).to_vec(),
+                Some(lists) => lists
+                    .iter()
+                    .flat_map(|list| {
+                        let list = &self.lists[&url_safe(list.as_str().unwrap())];
+                        list.chunks(HASH_LEN)
+                            .map(|hash| self.block(&base64::encode(hash)).to_vec())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+                    .concat(),
+            };
+            assert_eq!(base64::encode(&sha256(&contents)), hash);
+            assert_eq!(
+                entry.get("size").and_then(Json::as_u64),
+                Some(contents.len() as u64)
+            );
+            contents
+        }
+
+        fn metadata(&self, entry: &Json) -> Result<Json> {
+            let hash = entry.get("metahash").and_then(Json::as_str).unwrap();
+            let metadata = self.block(hash);
+            assert_eq!(
+                entry.get("metasize").and_then(Json::as_u64),
+                Some(metadata.len() as u64)
+            );
+            Json::parse(metadata)
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "disk-drive-duplicati-{:016x}",
+            rand::random::<u64>()
+        ))
+    }
+
+    #[tokio::test]
+    async fn volumes_stay_under_the_zip_entry_limit() -> Result<()> {
+        let dir = temp_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+        let sink = DuplicatiSink::new(&dir).block_size(BLOCK_SIZE);
+        let mut volumes = Volumes::new(&sink, SystemTime::now());
+        // tiny blocks, so only the entry count closes volumes, and every one
+        // a blocklist, since those take up entries in the dindex too
+        let count = MAX_VOLUME_BLOCKS as u32 + 10;
+        for i in 0..count {
+            volumes.add_blocklist(&i.to_le_bytes()).await?;
+        }
+        volumes.finish(Json::Array(vec![])).await?;
+        let set = Set::read(&dir);
+        std::fs::remove_dir_all(&dir)?;
+
+        let set = set?;
+        assert_eq!(set.dblocks, 2);
+        assert_eq!(set.blocks.len(), count as usize);
+        assert_eq!(set.lists.len(), count as usize);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_sets_that_restore() -> Result<()> {
+        let shared = [b's'; BLOCK_SIZE];
+        let first = [&[b'a'; BLOCK_SIZE][..], &shared, &shared, b"tail"].concat();
+        let second = [&shared[..], &[b'b'; BLOCK_SIZE]].concat();
+        // more blocks than one blocklist block has room for
+        let long: Vec<u8> = (0..BLOCK_SIZE * 5)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/docs").await?;
+        src.write("/docs/first.bin", &first).await?;
+        src.write("/docs/second.bin", &second).await?;
+        src.write("/docs/shared.bin", shared).await?;
+        src.write("/long.bin", &long).await?;
+        src.write("/empty", "").await?;
+        src.set_permissions("/docs/first.bin", MemPermissions::from_mode(0o640))
+            .await?;
+        src.chown("/docs/first.bin", 1234, 5678).await?;
+        src.symlink(PathBuf::from("docs/first.bin"), PathBuf::from("/link"))
+            .await?;
+
+        let dir = temp_dir();
+        let dest = DuplicatiFloppyDisk::new(
+            DuplicatiSink::new(&dir)
+                .prefix("test")
+                .block_size(BLOCK_SIZE)
+                .volume_size(4 * BLOCK_SIZE),
+        );
+        let summary = DiskDrive::copy_between(&src, &dest).await?;
+        let set = Set::read(&dir)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(set.dlists.len(), 1);
+        assert_eq!(summary.snapshot_id.as_ref(), Some(&set.dlists[0].0));
+        assert!(set.dlists[0].0.starts_with("test-"));
+        assert!(set.dblocks > 1);
+
+        let files = set.filelist()?;
+        let paths: Vec<_> = files.keys().map(String::as_str).collect();
+        assert!(paths.contains(&"/docs/"), "{paths:?}");
+        for (path, contents) in [
+            ("/docs/first.bin", &first[..]),
+            ("/docs/second.bin", &second),
+            ("/docs/shared.bin", &shared),
+            ("/long.bin", &long),
+            ("/empty", b""),
+        ] {
+            let entry = &files[path];
+            assert_eq!(entry.get("type").and_then(Json::as_str), Some("File"));
+            assert_eq!(set.contents(entry), contents, "{path}");
+        }
+
+        // single-block files are found by their own hash, and the shared
+        // block is the same block everywhere it's used
+        assert!(files["/docs/shared.bin"].get("blocklists").is_none());
+        let shared_hash = base64::encode(&sha256(&shared));
+        assert_eq!(
+            files["/docs/shared.bin"].get("hash").and_then(Json::as_str),
+            Some(shared_hash.as_str())
+        );
+        let data_blocks = [
+            &[b'a'; BLOCK_SIZE][..],
+            &shared,
+            b"tail",
+            &[b'b'; BLOCK_SIZE],
+            b"",
+        ]
+        .iter()
+        .map(|block| url_safe(&base64::encode(&sha256(block))))
+        .chain(
+            long.chunks(BLOCK_SIZE)
+                .map(|block| url_safe(&base64::encode(&sha256(block)))),
+        )
+        .collect::<HashSet<_>>();
+        assert!(data_blocks.iter().all(|hash| set.blocks.contains_key(hash)));
+        assert_eq!(
+            files["/long.bin"]
+                .get("blocklists")
+                .and_then(Json::as_array)
+                .map(<[Json]>::len),
+            Some(3)
+        );
+
+        let metadata = set.metadata(&files["/docs/first.bin"])?;
+        assert_eq!(
+            metadata.get("unix:uid-gid-perm").and_then(Json::as_str),
+            Some("1234-5678-416")
+        );
+        let link = &files["/link"];
+        assert_eq!(link.get("type").and_then(Json::as_str), Some("Symlink"));
+        assert_eq!(
+            set.metadata(link)?
+                .get("CoreSymlinkTarget")
+                .and_then(Json::as_str),
+            Some("docs/first.bin")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_versions_in_the_same_directory() -> Result<()> {
+        let src = MemFloppyDisk::new();
+        src.write("/file", "hello").await?;
+
+        let dir = temp_dir();
+        let dest = DuplicatiFloppyDisk::new(DuplicatiSink::new(&dir).block_size(BLOCK_SIZE));
+        DiskDrive::copy_between(&src, &dest).await?;
+        // dlists are named by the second, so make sure the next one isn't
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        src.write("/file", "hello again").await?;
+        DiskDrive::copy_between(&src, &dest).await?;
+        let set = Set::read(&dir)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(set.dlists.len(), 2);
+        assert_eq!(set.contents(&set.filelist()?["/file"]), b"hello again");
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+//! Uploads to an FTP or FTPS server with `suppaftp`, over a single control
+//! connection for the whole copy.
+//!
+//! Transfers are passive and binary. FTP has no atomic rename, so each file
+//! is uploaded under a temporary name and renamed into place once the
+//! transfer has finished, so a half-written file is never visible under its
+//! real name. FTPS uses `rustls`, and trusts the Mozilla roots unless
+//! [`FtpSink::tls_config`] says otherwise.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use derivative::Derivative;
+use eyre::Result;
+use suppaftp::tokio::{AsyncRustlsConnector, AsyncRustlsFtpStream};
+use suppaftp::tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use suppaftp::tokio_rustls::TlsConnector;
+use suppaftp::types::FileType;
+use tracing::{debug, warn};
+
+use crate::staged::{Sink, Stage, StagedFloppyDisk, StagedKind};
+use crate::CopySummary;
+
+pub type FtpFloppyDisk = StagedFloppyDisk<FtpSink>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FtpTls {
+    /// Plain FTP.
+    #[default]
+    None,
+    /// Explicit FTPS: connect in plain text and upgrade with `AUTH TLS`.
+    /// Fails if the server doesn't support it.
+    Explicit,
+    /// Implicit FTPS: TLS from the start, usually on port 990.
+    Implicit,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct FtpSink {
+    host: String,
+    port: Option<u16>,
+    user: String,
+    #[derivative(Debug = "ignore")]
+    password: String,
+    root: PathBuf,
+    tls: FtpTls,
+    #[derivative(Debug = "ignore")]
+    tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl FtpSink {
+    /// `root` is relative to the login directory unless it starts with `/`.
+    /// It can go above the login directory with `..`.
+    pub fn new<H: Into<String>, U: Into<String>, S: Into<String>, P: Into<PathBuf>>(
+        host: H,
+        user: U,
+        password: S,
+        root: P,
+    ) -> Self {
+        Self {
+            host: host.into(),
+            port: None,
+            user: user.into(),
+            password: password.into(),
+            root: normalize(&root.into()),
+            tls: FtpTls::None,
+            tls_config: None,
+        }
+    }
+
+    /// Defaults to 990 for [`FtpTls::Implicit`], and 21 otherwise.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn tls(mut self, tls: FtpTls) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// The `rustls` config to connect with, for servers whose certificates
+    /// don't chain up to one of the Mozilla roots.
+    pub fn tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    fn connector(&self) -> Result<AsyncRustlsConnector> {
+        let config = match &self.tls_config {
+            Some(config) => config.clone(),
+            None => {
+                let roots =
+                    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                // the provider is picked explicitly, since another crate in
+                // the build may enable a second one
+                let config = ClientConfig::builder_with_provider(Arc::new(
+                    rustls::crypto::ring::default_provider(),
+                ))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+                Arc::new(config)
+            }
+        };
+        Ok(TlsConnector::from(config).into())
+    }
+
+    /// Connects, logs in, and switches to binary transfers.
+    async fn connect(&self) -> Result<AsyncRustlsFtpStream> {
+        let port = self.port.unwrap_or(match self.tls {
+            FtpTls::Implicit => 990,
+            _ => 21,
+        });
+        let address = (self.host.as_str(), port);
+        let mut ftp = match self.tls {
+            FtpTls::None => AsyncRustlsFtpStream::connect(address).await?,
+            FtpTls::Explicit => {
+                AsyncRustlsFtpStream::connect(address)
+                    .await?
+                    .into_secure(self.connector()?, &self.host)
+                    .await?
+            }
+            FtpTls::Implicit => {
+                AsyncRustlsFtpStream::connect_secure_implicit(
+                    address,
+                    self.connector()?,
+                    &self.host,
+                )
+                .await?
+            }
+        };
+        // the address in a PASV reply is often a private one from behind
+        // NAT, so data connections go to the control connection's address
+        ftp.set_passive_nat_workaround(true);
+        ftp.login(&self.user, &self.password).await?;
+        ftp.transfer_type(FileType::Binary).await?;
+        Ok(ftp)
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for FtpSink {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> Result<()> {
+        let mut ftp = self.connect().await?;
+
+        let mut dirs: Vec<PathBuf> = self
+            .root
+            .ancestors()
+            .skip(1)
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.reverse();
+        dirs.push(self.root.clone());
+        dirs.extend(
+            stage
+                .entries()
+                .iter()
+                .filter(|entry| entry.kind == StagedKind::Dir)
+                .map(|entry| self.root.join(entry.relative_path())),
+        );
+        dirs.retain(|dir| {
+            !dir.as_os_str().is_empty()
+                && dir != Path::new("/")
+                && dir.components().next_back() != Some(Component::ParentDir)
+        });
+        debug!("ftp mkdir {} dirs", dirs.len());
+        for dir in &dirs {
+            // MKD fails for directories that already exist
+            if let Err(e) = ftp.mkdir(remote(dir)).await {
+                debug!("ftp mkdir {} failed: {e}", dir.display());
+            }
+        }
+
+        for entry in stage.entries() {
+            let path = self.root.join(entry.relative_path());
+            match &entry.kind {
+                StagedKind::Dir => {}
+                StagedKind::File { .. } => {
+                    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+                        continue;
+                    };
+                    let temp = remote(&parent.join(format!(
+                        ".{}.disk-drive-partial",
+                        file_name.to_string_lossy()
+                    )));
+                    let target = remote(&path);
+
+                    debug!("ftp upload {target}");
+                    ftp.put_file(&temp, &mut stage.open(entry).await?).await?;
+                    // RNTO won't replace an existing file on every server
+                    if let Err(e) = ftp.rm(&target).await {
+                        debug!("ftp delete {target} failed: {e}");
+                    }
+                    ftp.rename(&temp, &target).await?;
+                    // SITE CHMOD is an extension, so not every server has it
+                    if let Err(e) = ftp.site(format!("CHMOD {:o} {target}", entry.mode)).await {
+                        debug!("ftp chmod {target} failed: {e}");
+                    }
+                }
+                StagedKind::Symlink { target } => {
+                    warn!(
+                        "ftp can't store symlinks, skipping {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                    summary.symlinks = summary.symlinks.saturating_sub(1);
+                }
+            }
+        }
+
+        ftp.quit().await?;
+        Ok(())
+    }
+}
+
+/// A normalized path as the server is sent it.
+fn remote(path: &Path) -> String {
+    normalize(path).to_string_lossy().to_string()
+}
+
+/// Resolves `.` and `..` without looking at the server. `..` at the start
+/// of a relative path is kept, since it's relative to a login directory
+/// that isn't known here, and `..` at `/` stays at `/`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::Mutex;
+
+    use floppy_disk::mem::MemPermissions;
+    use floppy_disk::prelude::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::DiskDrive;
+
+    fn sink<P: Into<PathBuf>>(root: P) -> FtpSink {
+        FtpSink::new("example.com", "user", "password", root)
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        let sink = sink("./backups");
+        assert_eq!(sink.root, PathBuf::from("backups"));
+        assert_eq!(remote(&sink.root.join("a/b.txt")), "backups/a/b.txt");
+        assert_eq!(remote(Path::new("/srv/./old/../new")), "/srv/new");
+        assert_eq!(remote(Path::new("/../etc")), "/etc");
+    }
+
+    #[test]
+    fn keeps_leading_parent_dirs() {
+        let sink = sink("../shared/./backups/");
+        assert_eq!(sink.root, PathBuf::from("../shared/backups"));
+        assert_eq!(remote(&sink.root.join("f")), "../shared/backups/f");
+        assert_eq!(remote(Path::new("a/../../b")), "../b");
+    }
+
+    /// What the stub server has been sent, and what it's storing.
+    #[derive(Debug, Default)]
+    struct Server {
+        commands: Vec<String>,
+        dirs: BTreeSet<String>,
+        files: BTreeMap<String, Vec<u8>>,
+        /// Refuse uploads, as a server that's out of space does.
+        full: bool,
+    }
+
+    /// Enough of an FTP server to upload into.
+    async fn ftp_server(server: Server) -> (u16, Arc<Mutex<Server>>) {
+        let server = Arc::new(Mutex::new(server));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn({
+            let server = server.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(session(stream, server.clone()));
+                }
+            }
+        });
+        (port, server)
+    }
+
+    async fn session(stream: TcpStream, server: Arc<Mutex<Server>>) -> std::io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 ready\r\n").await?;
+        let mut passive = None;
+        let mut rename_from = None;
+        while let Some(line) = lines.next_line().await? {
+            let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+            let arg = arg.to_string();
+            if command != "PASV" {
+                let logged = if command == "PASS" { "PASS ***" } else { &line };
+                server.lock().unwrap().commands.push(logged.to_string());
+            }
+
+            let reply = match command {
+                "USER" => "331 password please".to_string(),
+                "PASS" if arg == "password" => "230 logged in".to_string(),
+                "PASS" => "530 wrong password".to_string(),
+                "TYPE" if arg == "I" => "200 binary".to_string(),
+                "MKD" if server.lock().unwrap().dirs.insert(arg.clone()) => {
+                    "257 created".to_string()
+                }
+                "MKD" => "550 already exists".to_string(),
+                "PASV" => {
+                    let listener = TcpListener::bind("127.0.0.1:0").await?;
+                    let port = listener.local_addr()?.port();
+                    passive = Some(listener);
+                    format!("227 passive (127,0,0,1,{},{})", port >> 8, port & 0xff)
+                }
+                "STOR" => {
+                    let Some(listener) = passive.take() else {
+                        write.write_all(b"425 use PASV first\r\n").await?;
+                        continue;
+                    };
+                    if server.lock().unwrap().full {
+                        "552 out of space".to_string()
+                    } else {
+                        write.write_all(b"150 send it\r\n").await?;
+                        let (mut data, _) = listener.accept().await?;
+                        let mut contents = vec![];
+                        data.read_to_end(&mut contents).await?;
+                        server.lock().unwrap().files.insert(arg, contents);
+                        "226 stored".to_string()
+                    }
+                }
+                "DELE" => match server.lock().unwrap().files.remove(&arg) {
+                    Some(_) => "250 deleted".to_string(),
+                    None => "550 no such file".to_string(),
+                },
+                "RNFR" if server.lock().unwrap().files.contains_key(&arg) => {
+                    rename_from = Some(arg);
+                    "350 go on".to_string()
+                }
+                "RNFR" => "550 no such file".to_string(),
+                "RNTO" => {
+                    let mut server = server.lock().unwrap();
+                    match rename_from
+                        .take()
+                        .and_then(|from| server.files.remove(&from))
+                    {
+                        Some(contents) => {
+                            server.files.insert(arg, contents);
+                            "250 renamed".to_string()
+                        }
+                        None => "503 RNFR first".to_string(),
+                    }
+                }
+                "SITE" if arg.starts_with("CHMOD ") => "200 chmoded".to_string(),
+                "QUIT" => {
+                    write.write_all(b"221 bye\r\n").await?;
+                    break;
+                }
+                _ => "502 not implemented".to_string(),
+            };
+            write.write_all(format!("{reply}\r\n").as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn source() -> Result<MemFloppyDisk> {
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/docs/empty-dir").await?;
+        src.write("/docs/a.txt", "hello").await?;
+        src.set_permissions("/docs/a.txt", MemPermissions::from_mode(0o640))
+            .await?;
+        src.write("/docs/empty", "").await?;
+        src.set_permissions("/docs/empty", MemPermissions::from_mode(0o600))
+            .await?;
+        src.symlink(PathBuf::from("docs/a.txt"), PathBuf::from("/link"))
+            .await?;
+        Ok(src)
+    }
+
+    #[tokio::test]
+    async fn uploads_through_temp_files() -> Result<()> {
+        let (port, server) = ftp_server(Server {
+            dirs: BTreeSet::from(["../shared".to_string()]),
+            files: BTreeMap::from([("../shared/backups/docs/a.txt".to_string(), b"old".to_vec())]),
+            ..Server::default()
+        })
+        .await;
+        let dest = FtpFloppyDisk::new(
+            FtpSink::new("127.0.0.1", "user", "password", "../shared/./backups").port(port),
+        );
+
+        let summary = DiskDrive::copy_between(&source().await?, &dest).await?;
+        assert_eq!(summary.files, 2);
+        // the symlink was skipped, so it isn't counted
+        assert_eq!(summary.symlinks, 0);
+
+        let server = server.lock().unwrap();
+        assert_eq!(
+            server.files,
+            BTreeMap::from([
+                (
+                    "../shared/backups/docs/a.txt".to_string(),
+                    b"hello".to_vec()
+                ),
+                ("../shared/backups/docs/empty".to_string(), vec![]),
+            ])
+        );
+        assert_eq!(
+            server.commands,
+            [
+                "USER user",
+                "PASS ***",
+                "TYPE I",
+                // parents first, and already existing is fine
+                "MKD ../shared",
+                "MKD ../shared/backups",
+                "MKD ../shared/backups/docs",
+                "MKD ../shared/backups/docs/empty-dir",
+                "STOR ../shared/backups/docs/.a.txt.disk-drive-partial",
+                "DELE ../shared/backups/docs/a.txt",
+                "RNFR ../shared/backups/docs/.a.txt.disk-drive-partial",
+                "RNTO ../shared/backups/docs/a.txt",
+                "SITE CHMOD 640 ../shared/backups/docs/a.txt",
+                "STOR ../shared/backups/docs/.empty.disk-drive-partial",
+                "DELE ../shared/backups/docs/empty",
+                "RNFR ../shared/backups/docs/.empty.disk-drive-partial",
+                "RNTO ../shared/backups/docs/empty",
+                "SITE CHMOD 600 ../shared/backups/docs/empty",
+                "QUIT",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_when_uploads_are_refused() -> Result<()> {
+        let (port, server) = ftp_server(Server {
+            full: true,
+            ..Server::default()
+        })
+        .await;
+        let dest =
+            FtpFloppyDisk::new(FtpSink::new("127.0.0.1", "user", "password", "/").port(port));
+        assert!(DiskDrive::copy_between(&source().await?, &dest)
+            .await
+            .is_err());
+
+        {
+            let server = server.lock().unwrap();
+            assert!(server.files.is_empty());
+            // nothing was renamed into place
+            assert!(!server.commands.iter().any(|c| c.starts_with("RNFR")));
+        }
+
+        let dest = FtpFloppyDisk::new(FtpSink::new("127.0.0.1", "user", "wrong", "/").port(port));
+        assert!(DiskDrive::copy_between(&source().await?, &dest)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+//! Just enough JSON to talk to the APIs the remote backends use.
+
+use std::fmt::{Display, Write};
+
+#[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+use eyre::{eyre, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    /// Only ever parsed.
+    #[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+    Null,
+    Bool(bool),
+    /// Kept as written, since going through `f64` would lose precision on
+    /// integers past 2^53.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    #[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+    pub(crate) fn parse<S: AsRef<[u8]>>(input: S) -> Result<Self> {
+        let input = input.as_ref();
+        let mut parser = Parser { input, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != input.len() {
+            return Err(eyre!("trailing characters in json at byte {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    #[cfg(any(test, feature = "duplicati", feature = "rclone", feature = "restic"))]
+    pub(crate) fn object<K: Into<String>, I: IntoIterator<Item = (K, Json)>>(fields: I) -> Self {
+        Self::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    #[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(test, feature = "pcloud", feature = "restic"))]
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(test, feature = "restic"))]
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(test, feature = "restic"))]
+    pub(crate) fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Self::Number(value.to_string())
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(value: Vec<Json>) -> Self {
+        Self::Array(value)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => f.write_str(n),
+            Self::String(s) => write_string(f, s),
+            Self::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+#[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+#[cfg(any(test, feature = "pcloud", feature = "rclone", feature = "restic"))]
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        match self.peek() {
+            Some(b) if b == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(eyre!(
+                "expected '{}' in json at byte {}",
+                byte as char,
+                self.pos
+            )),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json> {
+        if self.input[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(eyre!("invalid literal in json at byte {}", self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(values))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(fields))
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(eyre!("unexpected input in json at byte {}", self.pos)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos])?;
+        // only parsed to check it's a number
+        text.parse::<f64>()
+            .map_err(|_| eyre!("invalid number in json at byte {start}"))?;
+        Ok(Json::Number(text.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if self.input.get(self.pos) != Some(&b'"') {
+            return Err(eyre!("expected string in json at byte {}", self.pos));
+        }
+        self.pos += 1;
+
+        let mut out = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                None => return Err(eyre!("unterminated string in json")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.input.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let high = self.hex4()?;
+                            let code = if (0xd800..0xdc00).contains(&high) {
+                                if !self.input[self.pos + 1..].starts_with(b"\\u") {
+                                    return Err(eyre!("unpaired surrogate in json"));
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(eyre!("unpaired surrogate in json"));
+                                }
+                                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                            } else {
+                                high
+                            };
+                            char::from_u32(code)
+                                .ok_or_else(|| eyre!("invalid unicode escape in json"))?
+                        }
+                        _ => return Err(eyre!("invalid escape in json at byte {}", self.pos)),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                    self.pos += 1;
+                }
+                Some(b) => {
+                    out.push(*b);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        Ok(String::from_utf8(out)?)
+    }
+
+    /// Reads the four hex digits after a `\u`, leaving `pos` on the last one.
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos + 1..self.pos + 5)
+            .ok_or_else(|| eyre!("truncated unicode escape in json"))?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(eyre!("invalid unicode escape in json at byte {}", self.pos));
+        }
+        self.pos += 4;
+        Ok(u32::from_str_radix(std::str::from_utf8(digits)?, 16)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values() {
+        let json = Json::parse(r#" {"a": [1, true, null, "x"], "b": {"c": -2.5e3}} "#).unwrap();
+        let a = json.get("a").and_then(Json::as_array).unwrap();
+        assert_eq!(a[0].as_u64(), Some(1));
+        assert_eq!(a[1].as_bool(), Some(true));
+        assert_eq!(a[2], Json::Null);
+        assert_eq!(a[3].as_str(), Some("x"));
+        let c = json.get("b").and_then(|b| b.get("c")).unwrap();
+        assert_eq!(c, &Json::Number("-2.5e3".into()));
+        assert_eq!(c.as_u64(), None);
+    }
+
+    #[test]
+    fn keeps_integers_past_f64_precision() {
+        let json = Json::parse("9007199254740993").unwrap();
+        assert_eq!(json.as_u64(), Some(9007199254740993));
+        assert_eq!(json.to_string(), "9007199254740993");
+        assert_eq!(Json::from(u64::MAX).to_string(), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn decodes_escapes_and_surrogate_pairs() {
+        let json = Json::parse(r#""a\"\\\/\né😀""#).unwrap();
+        assert_eq!(json.as_str(), Some("a\"\\/\né😀"));
+    }
+
+    #[test]
+    fn rejects_broken_surrogates() {
+        assert!(Json::parse(r#""\ud800\u0041""#).is_err());
+        assert!(Json::parse(r#""\ud800A""#).is_err());
+        assert!(Json::parse(r#""\ud800""#).is_err());
+        assert!(Json::parse(r#""\ud800x""#).is_err());
+        assert!(Json::parse(r#""\udc00""#).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in [
+            "",
+            "{",
+            "[1,",
+            r#"{"a" 1}"#,
+            "tru",
+            "1 2",
+            r#""\u+123""#,
+            r#""\u12""#,
+            "1-2",
+            r#""unterminated"#,
+        ] {
+            assert!(Json::parse(input).is_err(), "{input:?} should fail");
+        }
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let json = Json::object([
+            ("s", "quote \" tab \t ctrl \u{1}".into()),
+            ("n", 42u64.into()),
+            ("a", vec![Json::Null, true.into()].into()),
+        ]);
+        assert_eq!(Json::parse(json.to_string()).unwrap(), json);
+    }
+}
@@ -6,11 +6,84 @@ use floppy_disk::prelude::*;
 use tokio::io::AsyncWriteExt;
 use tracing::{error, trace, warn};
 
+#[cfg(any(feature = "duplicati", feature = "restic"))]
+mod base64;
+#[cfg(feature = "borg")]
+pub mod borg;
+#[cfg(feature = "restic")]
+mod chunker;
+#[cfg(feature = "restic")]
+mod crypto;
+#[cfg(any(feature = "pcloud", feature = "restic"))]
+mod curl;
+#[cfg(feature = "duplicati")]
+pub mod duplicati;
+#[cfg(feature = "ftp")]
+pub mod ftp;
+#[cfg(any(
+    feature = "duplicati",
+    feature = "pcloud",
+    feature = "rclone",
+    feature = "restic"
+))]
+mod json;
+#[cfg(feature = "pcloud")]
+pub mod pcloud;
+#[cfg(feature = "rclone")]
+pub mod rclone;
+#[cfg(feature = "restic")]
+pub mod restic;
+#[cfg(feature = "scp")]
+pub mod scp;
+#[cfg(any(feature = "duplicati", feature = "restic"))]
+mod sha256;
+pub mod staged;
+#[cfg(feature = "borg")]
+mod tar;
+#[cfg(all(test, any(feature = "pcloud", feature = "rclone", feature = "restic")))]
+mod test_server;
+#[cfg(any(feature = "duplicati", feature = "restic"))]
+mod time;
+#[cfg(feature = "duplicati")]
+mod zip;
+
+/// What a copy did. Disks that push their contents somewhere else once the
+/// copy is done can add to this from [`FloppyDiskFinalize::finalize`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopySummary {
+    pub files: u64,
+    pub dirs: u64,
+    /// Destinations that can't store symlinks skip them, and don't count
+    /// them here.
+    pub symlinks: u64,
+    pub bytes: u64,
+    /// Set by backup destinations that record each copy as a snapshot.
+    pub snapshot_id: Option<String>,
+}
+
+/// Called on the destination disk once every path has been copied into it.
+#[async_trait::async_trait]
+pub trait FloppyDiskFinalize {
+    async fn finalize(&self, _summary: &mut CopySummary) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of [`FloppyDiskFinalize::finalize`] when a copy fails
+    /// partway, so disks that collect a copy can throw away what they have.
+    async fn abort(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl FloppyDiskFinalize for MemFloppyDisk {}
+
+impl FloppyDiskFinalize for TokioFloppyDisk {}
+
 pub struct DiskDrive<
     'a,
     'b,
     F1: FloppyDisk<'a> + FloppyDiskUnixExt + Send + Sync + 'a,
-    F2: FloppyDisk<'b> + FloppyDiskUnixExt + Send + Sync + 'b,
+    F2: FloppyDisk<'b> + FloppyDiskUnixExt + FloppyDiskFinalize + Send + Sync + 'b,
 > where
     <F1 as FloppyDisk<'a>>::Permissions: FloppyUnixPermissions,
     <F1 as FloppyDisk<'a>>::Metadata: FloppyUnixMetadata,
@@ -44,7 +117,7 @@ impl<
         'a,
         'b,
         F1: FloppyDisk<'a> + FloppyDiskUnixExt + Send + Sync + 'a,
-        F2: FloppyDisk<'b> + FloppyDiskUnixExt + Send + Sync + 'b,
+        F2: FloppyDisk<'b> + FloppyDiskUnixExt + FloppyDiskFinalize + Send + Sync + 'b,
     > DiskDrive<'a, 'b, F1, F2>
 where
     <F1 as FloppyDisk<'a>>::Permissions: FloppyUnixPermissions,
@@ -52,7 +125,7 @@ where
     <F2 as FloppyDisk<'b>>::Permissions: FloppyUnixPermissions,
     <F2 as FloppyDisk<'b>>::Metadata: FloppyUnixMetadata,
 {
-    pub async fn copy_between(src: &'a F1, dest: &'b F2) -> Result<()> {
+    pub async fn copy_between(src: &'a F1, dest: &'b F2) -> Result<CopySummary> {
         Self::do_copy(src, dest, None, None).await
     }
 
@@ -60,7 +133,7 @@ where
         src: &'a F1,
         dest: &'b F2,
         src_scope: P,
-    ) -> Result<()> {
+    ) -> Result<CopySummary> {
         let src_scope = src_scope.into();
         let src_scope = if !src_scope.starts_with("/") {
             PathBuf::from("/").join(src_scope)
@@ -74,7 +147,7 @@ where
         src: &'a F1,
         dest: &'b F2,
         dest_scope: P,
-    ) -> Result<()> {
+    ) -> Result<CopySummary> {
         let dest_scope = dest_scope.into();
         let dest_scope = if !dest_scope.starts_with("/") {
             PathBuf::from("/").join(dest_scope)
@@ -89,7 +162,7 @@ where
         dest: &'b F2,
         src_scope: P,
         dest_scope: Q,
-    ) -> Result<()> {
+    ) -> Result<CopySummary> {
         let src_scope = src_scope.into();
         let dest_scope = dest_scope.into();
         let src_scope = if !src_scope.starts_with("/") {
@@ -110,6 +183,26 @@ where
         dest: &'b F2,
         src_path: Option<PathBuf>,
         dest_path: Option<PathBuf>,
+    ) -> Result<CopySummary> {
+        let mut summary = CopySummary::default();
+        if let Err(e) = Self::copy_paths(src, dest, src_path, dest_path, &mut summary).await {
+            if let Err(abort) = dest.abort().await {
+                warn!("failed to abort copy: {abort}");
+            }
+            return Err(e);
+        }
+
+        dest.finalize(&mut summary).await?;
+
+        Ok(summary)
+    }
+
+    async fn copy_paths(
+        src: &'a F1,
+        dest: &'b F2,
+        src_path: Option<PathBuf>,
+        dest_path: Option<PathBuf>,
+        summary: &mut CopySummary,
     ) -> Result<()> {
         let src_path = src_path.unwrap_or_else(|| PathBuf::from("/"));
         let dest_path = dest_path.unwrap_or_else(|| PathBuf::from("/"));
@@ -132,6 +225,7 @@ where
                         dest_path.display()
                     );
                     Self::add_symlink_to_memfs(src, dest, &src_path, &dest_path).await?;
+                    summary.symlinks += 1;
                 }
                 Err(_) => {
                     let metadata = <F1 as FloppyDisk<'a>>::metadata(src, &src_path).await?;
@@ -139,13 +233,19 @@ where
                     if file_type.is_dir() {
                         trace!("copy dir {} -> {}", src_path.display(), dest_path.display());
                         Self::copy_dir_to_memfs(src, dest, &src_path, &dest_path).await?;
+                        summary.dirs += 1;
                     } else if file_type.is_file() {
                         trace!(
                             "copy file {} -> {}",
                             src_path.display(),
                             dest_path.display()
                         );
-                        Self::copy_file_to_memfs(src, dest, &src_path, &dest_path).await?;
+                        if let Some(written) =
+                            Self::copy_file_to_memfs(src, dest, &src_path, &dest_path).await?
+                        {
+                            summary.files += 1;
+                            summary.bytes += written;
+                        }
                     } else {
                         error!("unknown file type for source path {src_path:?}");
                     }
@@ -156,12 +256,14 @@ where
         Ok(())
     }
 
+    /// Returns how many bytes were written, or `None` if the file was
+    /// skipped.
     async fn copy_file_to_memfs(
         src: &'a F1,
         dest: &'b F2,
         src_path: &Path,
         dest_path: &Path,
-    ) -> Result<()> {
+    ) -> Result<Option<u64>> {
         dest.create_dir_all("/").await?;
         let dest_path = if !dest_path.starts_with("/") {
             PathBuf::from("/").join(dest_path)
@@ -216,7 +318,7 @@ where
                     .create_new(true)
                     .open(dest, dest_path)
                     .await?;
-                tokio::io::copy(&mut src_handle, &mut dest_handle).await?;
+                let written = tokio::io::copy(&mut src_handle, &mut dest_handle).await?;
 
                 // copy permissions
                 let src_metadata = src_handle.metadata().await?;
@@ -232,7 +334,7 @@ where
                 <F2 as FloppyDiskUnixExt>::chown(dest, dest_path, uid, gid).await?;
                 <F2 as FloppyDisk>::set_permissions(dest, dest_path, permissions).await?;
 
-                return Ok(());
+                return Ok(Some(written));
             }
 
             let mut dest_handle: <F2 as FloppyDisk>::File = <F2::OpenOptions>::new()
@@ -265,13 +367,13 @@ where
                 <F2 as FloppyDiskUnixExt>::chown(dest, &dest_path, uid, gid).await?;
                 <F2 as FloppyDisk>::set_permissions(dest, &dest_path, permissions).await?;
 
-                return Ok(());
+                return Ok(Some(written));
             }
 
             // if dest exists and is a file, copy into it
             if dest_metadata.is_file() {
                 trace!("overwriting dest file {dest_path:?}");
-                tokio::io::copy(&mut src_handle, &mut dest_handle).await?;
+                let written = tokio::io::copy(&mut src_handle, &mut dest_handle).await?;
 
                 // copy permissions
                 let src_metadata = src_handle.metadata().await?;
@@ -287,13 +389,13 @@ where
                 <F2 as FloppyDiskUnixExt>::chown(dest, dest_path, uid, gid).await?;
                 <F2 as FloppyDisk>::set_permissions(dest, dest_path, permissions).await?;
 
-                return Ok(());
+                return Ok(Some(written));
             }
 
             // if dest exists and is a symlink, log error and return
             if dest_metadata.is_symlink() {
                 warn!("dest file path {dest_path:?} is a symlink, skipping copy!");
-                return Ok(());
+                return Ok(None);
             }
         }
 
@@ -307,7 +409,7 @@ where
         <F2 as FloppyDiskUnixExt>::chown(dest, dest_path, uid, gid).await?;
         <F2 as FloppyDisk>::set_permissions(dest, dest_path, permissions).await?;
 
-        Ok(())
+        Ok(None)
     }
 
     async fn copy_dir_to_memfs(
@@ -0,0 +1,387 @@
+//! Uploads to a folder in pCloud over its REST API.
+//!
+//! Requests are made with the system `curl`, which has to be on the `PATH`.
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use derivative::Derivative;
+use eyre::{eyre, Result};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::curl::Curl;
+use crate::json::Json;
+use crate::staged::{Sink, Stage, StagedFloppyDisk, StagedKind};
+use crate::CopySummary;
+
+/// pCloud's "user is over quota" error code.
+const OVER_QUOTA: u64 = 2008;
+
+pub type PCloudFloppyDisk = StagedFloppyDisk<PCloudSink>;
+
+/// pCloud keeps accounts in one of two regions, and each has its own API
+/// host.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PCloudRegion {
+    #[default]
+    Us,
+    Eu,
+}
+
+impl PCloudRegion {
+    fn api_host(&self) -> &'static str {
+        match self {
+            Self::Us => "https://api.pcloud.com",
+            Self::Eu => "https://eapi.pcloud.com",
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct PCloudSink {
+    #[derivative(Debug = "ignore")]
+    access_token: String,
+    api_host: String,
+    folder: PathBuf,
+}
+
+impl PCloudSink {
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(
+        access_token: S,
+        region: PCloudRegion,
+        folder: P,
+    ) -> Self {
+        let folder = folder.into();
+        let folder = if !folder.starts_with("/") {
+            PathBuf::from("/").join(folder)
+        } else {
+            folder
+        };
+        Self {
+            access_token: access_token.into(),
+            api_host: region.api_host().to_string(),
+            folder,
+        }
+    }
+
+    #[cfg(test)]
+    fn api_host<S: Into<String>>(mut self, api_host: S) -> Self {
+        self.api_host = api_host.into();
+        self
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        params: &[(&str, String)],
+        body: Option<PathBuf>,
+    ) -> Result<Json> {
+        let url = Url::parse_with_params(&format!("{}/{method}", self.api_host), params)?;
+        let mut request = Curl::new(url.as_str())
+            .header("Authorization", format!("Bearer {}", self.access_token));
+        if let Some(body) = body {
+            request = request.upload(body);
+        }
+        let response = request.send().await?;
+        if response.status != 200 {
+            return Err(eyre!("pcloud {method} returned http {}", response.status));
+        }
+
+        let json = Json::parse(&response.body)?;
+        match json.get("result").and_then(Json::as_u64) {
+            Some(0) => Ok(json),
+            Some(code) => Err(PCloudError {
+                method: method.to_string(),
+                code,
+                message: json
+                    .get("error")
+                    .and_then(Json::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            }
+            .into()),
+            None => Err(eyre!("pcloud {method} returned no result")),
+        }
+    }
+
+    async fn quota(&self) -> Result<(u64, u64)> {
+        let info = self.call("userinfo", &[], None).await?;
+        let used = info
+            .get("usedquota")
+            .and_then(Json::as_u64)
+            .ok_or_else(|| eyre!("pcloud userinfo is missing usedquota"))?;
+        let limit = info
+            .get("quota")
+            .and_then(Json::as_u64)
+            .ok_or_else(|| eyre!("pcloud userinfo is missing quota"))?;
+        Ok((used, limit))
+    }
+
+    /// `createfolderifnotexists` only creates the last path element, so
+    /// walk down from the root.
+    async fn create_folder_all(&self, path: &Path, created: &mut BTreeSet<PathBuf>) -> Result<()> {
+        let mut current = PathBuf::from("/");
+        for component in path.components().skip(1) {
+            current.push(component);
+            if created.contains(&current) {
+                continue;
+            }
+            debug!("pcloud mkdir {}", current.display());
+            self.call(
+                "createfolderifnotexists",
+                &[("path", current.display().to_string())],
+                None,
+            )
+            .await?;
+            created.insert(current.clone());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for PCloudSink {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> Result<()> {
+        let needed = stage.total_len();
+        let (used, limit) = self.quota().await?;
+        if used.saturating_add(needed) > limit {
+            return Err(QuotaExceededError {
+                used,
+                limit,
+                needed,
+            }
+            .into());
+        }
+
+        let mut created = BTreeSet::new();
+        self.create_folder_all(&self.folder, &mut created).await?;
+        for entry in stage.entries() {
+            let path = self.folder.join(entry.relative_path());
+            match &entry.kind {
+                StagedKind::Dir => self.create_folder_all(&path, &mut created).await?,
+                StagedKind::File { .. } => {
+                    let parent = path.parent().unwrap_or(&self.folder);
+                    self.create_folder_all(parent, &mut created).await?;
+                    let Some(file_name) = path.file_name() else {
+                        continue;
+                    };
+                    let mtime = entry.modified.duration_since(UNIX_EPOCH)?.as_secs();
+
+                    debug!("pcloud upload {}", path.display());
+                    let params = [
+                        ("path", parent.display().to_string()),
+                        ("filename", file_name.to_string_lossy().to_string()),
+                        ("nopartial", "1".to_string()),
+                        ("mtime", mtime.to_string()),
+                    ];
+                    match self
+                        .call("uploadfile", &params, stage.contents_path(entry))
+                        .await
+                    {
+                        Ok(_) => {}
+                        Err(e) => {
+                            if let Some(PCloudError {
+                                code: OVER_QUOTA, ..
+                            }) = e.downcast_ref()
+                            {
+                                let (used, limit) = self.quota().await?;
+                                return Err(QuotaExceededError {
+                                    used,
+                                    limit,
+                                    needed,
+                                }
+                                .into());
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                StagedKind::Symlink { target } => {
+                    warn!(
+                        "pcloud can't store symlinks, skipping {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                    summary.symlinks = summary.symlinks.saturating_sub(1);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The copy wouldn't fit in the account's storage. Free accounts get 5 GB.
+/// `used` and `limit` are as reported by pCloud, and `needed` is the total
+/// size of the files being copied, all in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceededError {
+    pub used: u64,
+    pub limit: u64,
+    pub needed: u64,
+}
+
+impl Display for QuotaExceededError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pcloud quota exceeded: {} bytes used of {}, {} more needed",
+            self.used, self.limit, self.needed
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceededError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PCloudError {
+    pub method: String,
+    pub code: u64,
+    pub message: String,
+}
+
+impl Display for PCloudError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pcloud {} failed with {}: {}",
+            self.method, self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for PCloudError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use floppy_disk::prelude::*;
+
+    use super::*;
+    use crate::test_server::serve;
+    use crate::DiskDrive;
+
+    fn userinfo(used: u64, quota: u64) -> Vec<u8> {
+        format!(r#"{{"result": 0, "usedquota": {used}, "quota": {quota}}}"#).into_bytes()
+    }
+
+    async fn source() -> Result<MemFloppyDisk> {
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/docs").await?;
+        src.write("/docs/a.txt", "0123456789").await?;
+        Ok(src)
+    }
+
+    #[tokio::test]
+    async fn uploads_into_the_folder() -> Result<()> {
+        let (url, requests) = serve(|request| {
+            if request.target.starts_with("/userinfo") {
+                (200, userinfo(0, 1000))
+            } else {
+                (200, br#"{"result": 0}"#.to_vec())
+            }
+        })
+        .await;
+        let dest = PCloudFloppyDisk::new(
+            PCloudSink::new("token", PCloudRegion::Eu, "backups").api_host(url),
+        );
+
+        let src = source().await?;
+        src.symlink(PathBuf::from("a.txt"), PathBuf::from("/docs/link"))
+            .await?;
+        let summary = DiskDrive::copy_between(&src, &dest).await?;
+        assert_eq!(summary.files, 1);
+        assert_eq!(summary.bytes, 10);
+        // the symlink was skipped, so it isn't counted
+        assert_eq!(summary.symlinks, 0);
+
+        let requests = requests.lock().unwrap();
+        let targets: Vec<_> = requests.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(targets[0], "/userinfo?");
+        assert!(targets.contains(&"/createfolderifnotexists?path=%2Fbackups"));
+        assert!(targets.contains(&"/createfolderifnotexists?path=%2Fbackups%2Fdocs"));
+        let upload = requests
+            .iter()
+            .find(|r| r.target.starts_with("/uploadfile?"))
+            .unwrap();
+        assert!(upload
+            .target
+            .starts_with("/uploadfile?path=%2Fbackups%2Fdocs&filename=a.txt&nopartial=1"));
+        assert_eq!(upload.body, b"0123456789");
+        assert!(requests
+            .iter()
+            .all(|r| r.header("authorization") == Some("Bearer token")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_copies_that_wont_fit() -> Result<()> {
+        // the second is a nonsense reply that would overflow
+        for (used, limit) in [(100, 105), (u64::MAX, 1000)] {
+            let (url, requests) = serve(move |_| (200, userinfo(used, limit))).await;
+            let dest = PCloudFloppyDisk::new(
+                PCloudSink::new("token", PCloudRegion::Us, "/backups").api_host(url),
+            );
+
+            let error = DiskDrive::copy_between(&source().await?, &dest)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<QuotaExceededError>(),
+                Some(&QuotaExceededError {
+                    used,
+                    limit,
+                    needed: 10,
+                })
+            );
+            // nothing was created or uploaded
+            assert_eq!(requests.lock().unwrap().len(), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_running_out_of_quota_mid_copy() -> Result<()> {
+        let used = Arc::new(AtomicU64::new(0));
+        let (url, _) = serve({
+            let used = used.clone();
+            move |request| {
+                if request.target.starts_with("/userinfo") {
+                    (200, userinfo(used.load(Ordering::SeqCst), 1000))
+                } else if request.target.starts_with("/uploadfile") {
+                    // someone else filled the account in the meantime
+                    used.store(995, Ordering::SeqCst);
+                    (
+                        200,
+                        br#"{"result": 2008, "error": "User is over quota."}"#.to_vec(),
+                    )
+                } else {
+                    (200, br#"{"result": 0}"#.to_vec())
+                }
+            }
+        })
+        .await;
+        let dest = PCloudFloppyDisk::new(
+            PCloudSink::new("token", PCloudRegion::Us, "/backups").api_host(url),
+        );
+
+        let error = DiskDrive::copy_between(&source().await?, &dest)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<QuotaExceededError>(),
+            Some(&QuotaExceededError {
+                used: 995,
+                limit: 1000,
+                needed: 10,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Copies through a running rclone's remote control API (`rclone rcd`, or
+//! any rclone started with `--rc`), so anything rclone can talk to works as
+//! a destination.
+//!
+//! Directories are made with `operations/mkdir`, and each staged file is
+//! copied with `operations/copyfile` straight from where it was spilled, so
+//! rclone has to be running on this host, as a user that can read the
+//! stage.
+
+use derivative::Derivative;
+use eyre::{eyre, Result};
+use reqwest::{Client, StatusCode};
+use tracing::{debug, warn};
+
+use crate::json::Json;
+use crate::staged::{Sink, Stage, StagedFloppyDisk, StagedKind};
+use crate::CopySummary;
+
+pub type RcloneFloppyDisk = StagedFloppyDisk<RcloneSink>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct RcloneSink {
+    #[derivative(Debug = "ignore")]
+    client: Client,
+    url: String,
+    #[derivative(Debug = "ignore")]
+    auth: Option<(String, String)>,
+    fs: String,
+}
+
+impl RcloneSink {
+    /// `fs` is an rclone remote and path, like `s3:bucket/backups`.
+    pub fn new<S: Into<String>>(fs: S) -> Self {
+        Self {
+            client: Client::new(),
+            url: "http://localhost:5572".into(),
+            auth: None,
+            fs: fs.into(),
+        }
+    }
+
+    /// Where rclone's rc server is listening. Defaults to rclone's own
+    /// default of `http://localhost:5572`.
+    pub fn url<S: Into<String>>(mut self, url: S) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// The `--rc-user` and `--rc-pass` rclone was started with.
+    pub fn auth<U: Into<String>, P: Into<String>>(mut self, user: U, password: P) -> Self {
+        self.auth = Some((user.into(), password.into()));
+        self
+    }
+
+    async fn call(&self, command: &str, params: Json) -> Result<Json> {
+        let url = format!("{}/{command}", self.url.trim_end_matches('/'));
+        let mut request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(params.to_string());
+        if let Some((user, password)) = &self.auth {
+            request = request.basic_auth(user, Some(password));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        if status != StatusCode::OK {
+            // rc errors are json, but auth failures and unknown commands
+            // might not be
+            let error = Json::parse(&body)
+                .ok()
+                .and_then(|json| json.get("error").and_then(Json::as_str).map(String::from))
+                .unwrap_or_else(|| String::from_utf8_lossy(&body).trim().to_string());
+            return Err(eyre!(
+                "rclone {command} failed with {}: {error}",
+                status.as_u16()
+            ));
+        }
+        Json::parse(&body)
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for RcloneSink {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> Result<()> {
+        self.call(
+            "operations/mkdir",
+            Json::object([("fs", self.fs.as_str().into()), ("remote", "".into())]),
+        )
+        .await?;
+        for entry in stage.entries() {
+            let remote = entry.relative_path().display().to_string();
+            match &entry.kind {
+                // copyfile makes parents as needed, but empty directories
+                // still need making by hand
+                StagedKind::Dir => {
+                    debug!("rclone mkdir {remote}");
+                    self.call(
+                        "operations/mkdir",
+                        Json::object([("fs", self.fs.as_str().into()), ("remote", remote.into())]),
+                    )
+                    .await?;
+                }
+                StagedKind::File { .. } => {
+                    let Some(contents) = stage.contents_path(entry) else {
+                        continue;
+                    };
+                    let (Some(dir), Some(file_name)) = (contents.parent(), contents.file_name())
+                    else {
+                        continue;
+                    };
+                    debug!("rclone copyfile {remote}");
+                    // the stage is an absolute path, so rclone won't take
+                    // anything in it for a remote's name
+                    self.call(
+                        "operations/copyfile",
+                        Json::object([
+                            ("srcFs", dir.to_string_lossy().as_ref().into()),
+                            ("srcRemote", file_name.to_string_lossy().as_ref().into()),
+                            ("dstFs", self.fs.as_str().into()),
+                            ("dstRemote", remote.into()),
+                        ]),
+                    )
+                    .await?;
+                }
+                StagedKind::Symlink { target } => {
+                    warn!(
+                        "rclone can't copy symlinks, skipping {remote} -> {}",
+                        target.display()
+                    );
+                    summary.symlinks = summary.symlinks.saturating_sub(1);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use floppy_disk::prelude::*;
+
+    use super::*;
+    use crate::test_server::serve;
+    use crate::DiskDrive;
+
+    #[tokio::test]
+    async fn copies_through_the_rc_api() -> Result<()> {
+        // stand in for rclone by reading each file from where copyfile says
+        // it is, as an rclone on the same host would
+        let (url, requests) = serve(|request| {
+            if request.target != "/operations/copyfile" {
+                return (200, b"{}".to_vec());
+            }
+            let params = Json::parse(&request.body).unwrap();
+            let get = |key| params.get(key).and_then(Json::as_str).unwrap();
+            let path = Path::new(get("srcFs")).join(get("srcRemote"));
+            let contents = std::fs::read(path).unwrap();
+            (
+                200,
+                format!(r#"{{"len": {}}}"#, contents.len()).into_bytes(),
+            )
+        })
+        .await;
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/empty").await?;
+        src.create_dir_all("/docs").await?;
+        src.write("/docs/a: b.txt", "contents").await?;
+        src.write("/top.txt", "top").await?;
+        src.symlink(PathBuf::from("docs"), PathBuf::from("/link"))
+            .await?;
+
+        let dest = RcloneFloppyDisk::new(
+            RcloneSink::new("remote:bucket/backups")
+                .url(format!("{url}/"))
+                .auth("rc", "secret"),
+        );
+        let summary = DiskDrive::copy_between(&src, &dest).await?;
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.symlinks, 0);
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<_> = requests
+            .iter()
+            .map(|r| (r.target.as_str(), Json::parse(&r.body).unwrap()))
+            .collect();
+        let mkdirs: Vec<_> = calls
+            .iter()
+            .filter(|(target, _)| *target == "/operations/mkdir")
+            .map(|(_, params)| {
+                assert_eq!(
+                    params.get("fs").and_then(Json::as_str),
+                    Some("remote:bucket/backups")
+                );
+                params.get("remote").and_then(Json::as_str).unwrap()
+            })
+            .collect();
+        assert_eq!(mkdirs, ["", "docs", "empty"]);
+
+        let copies: Vec<_> = calls
+            .iter()
+            .filter(|(target, _)| *target == "/operations/copyfile")
+            .map(|(_, params)| params)
+            .collect();
+        assert_eq!(copies.len(), 2);
+        for (params, remote) in copies.iter().zip(["docs/a: b.txt", "top.txt"]) {
+            let get = |key| params.get(key).and_then(Json::as_str);
+            assert!(get("srcFs").unwrap().starts_with('/'));
+            assert_eq!(get("dstFs"), Some("remote:bucket/backups"));
+            assert_eq!(get("dstRemote"), Some(remote));
+        }
+        assert_eq!(
+            copies[0].get("srcRemote").and_then(Json::as_str),
+            Some("a: b.txt")
+        );
+        assert!(
+            requests
+                .iter()
+                .all(|r| r.method == "POST"
+                    && r.header("authorization") == Some("Basic cmM6c2VjcmV0"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_rc_errors() -> Result<()> {
+        let (url, _) = serve(|_| {
+            (
+                500,
+                br#"{"error": "directory not found", "status": 500}"#.to_vec(),
+            )
+        })
+        .await;
+        let dest = RcloneFloppyDisk::new(RcloneSink::new("remote:").url(url));
+        let src = MemFloppyDisk::new();
+        src.write("/f", "x").await?;
+
+        let error = DiskDrive::copy_between(&src, &dest).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "rclone operations/mkdir failed with 500: directory not found"
+        );
+        Ok(())
+    }
+}
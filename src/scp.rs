@@ -0,0 +1,376 @@
+//! Uploads to a server over SCP, for hosts that don't have SFTP.
+//!
+//! SCP can only push and pull whole files, and can't list directories, so
+//! reads on an [`ScpFloppyDisk`] only see what has been staged into it.
+//! Directories are made with `mkdir -p` over SSH before the files are sent,
+//! and symlinks with `ln -s`, since SCP can't create them. SCP only sets a
+//! directory's mode when it uploads a file into it, so directories without
+//! any files get theirs with `chmod` over that same SSH command.
+//!
+//! This runs the system `ssh`, so it picks up `~/.ssh/config` and
+//! `ssh-agent` like any other SSH client would.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::UNIX_EPOCH;
+
+use eyre::{eyre, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, ChildStdout, Command};
+use tracing::{debug, trace};
+
+use crate::staged::{Sink, Stage, StagedFloppyDisk, StagedKind};
+use crate::CopySummary;
+
+pub type ScpFloppyDisk = StagedFloppyDisk<ScpSink>;
+
+#[derive(Debug, Clone)]
+pub struct ScpSink {
+    ssh: PathBuf,
+    destination: String,
+    port: Option<u16>,
+    identity_file: Option<PathBuf>,
+    ssh_options: Vec<String>,
+    root: PathBuf,
+}
+
+impl ScpSink {
+    /// `destination` is anything `ssh` accepts, like `user@host` or a
+    /// `Host` from `~/.ssh/config`.
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(destination: S, root: P) -> Self {
+        Self {
+            ssh: PathBuf::from("ssh"),
+            destination: destination.into(),
+            port: None,
+            identity_file: None,
+            ssh_options: vec![],
+            root: root.into(),
+        }
+    }
+
+    #[cfg(test)]
+    fn ssh_program<P: Into<PathBuf>>(mut self, ssh: P) -> Self {
+        self.ssh = ssh.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn identity_file<P: Into<PathBuf>>(mut self, identity_file: P) -> Self {
+        self.identity_file = Some(identity_file.into());
+        self
+    }
+
+    /// Passed to `ssh` as `-o <option>`, eg. `StrictHostKeyChecking=no`.
+    pub fn ssh_option<S: Into<String>>(mut self, option: S) -> Self {
+        self.ssh_options.push(option.into());
+        self
+    }
+
+    fn ssh(&self, remote_command: &str) -> Command {
+        let mut command = Command::new(&self.ssh);
+        command.args(["-o", "BatchMode=yes"]);
+        for option in &self.ssh_options {
+            command.arg("-o").arg(option);
+        }
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity_file) = &self.identity_file {
+            command.arg("-i").arg(identity_file);
+        }
+        command
+            .arg("--")
+            .arg(&self.destination)
+            .arg(remote_command)
+            .kill_on_drop(true);
+        command
+    }
+
+    async fn run(&self, remote_command: &str) -> Result<()> {
+        trace!("ssh {}: {remote_command}", self.destination);
+        let output = self
+            .ssh(remote_command)
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "ssh {} failed ({}): {}",
+                self.destination,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for ScpSink {
+    async fn flush(&self, stage: &Stage<'_>, _summary: &mut CopySummary) -> Result<()> {
+        let mut dir_modes = BTreeMap::new();
+        let mut command = format!("mkdir -p -- {}", quote(&self.root));
+        for entry in stage.entries() {
+            if entry.kind == StagedKind::Dir {
+                let path = self.root.join(entry.relative_path());
+                command.push(' ');
+                command.push_str(&quote(&path));
+                dir_modes.insert(entry.relative_path().to_path_buf(), entry.mode);
+            }
+        }
+        for entry in stage.entries() {
+            if let StagedKind::Symlink { target } = &entry.kind {
+                let path = self.root.join(entry.relative_path());
+                command.push_str(&format!(
+                    " && ln -sfn -- {} {}",
+                    quote(target),
+                    quote(&path)
+                ));
+            }
+        }
+        // D records set the modes of directories that files are uploaded
+        // into, so chmod the rest here. This comes after every `ln -s`, and
+        // deepest first, so a read-only mode doesn't get in the way.
+        let file_dirs: BTreeSet<&Path> = stage
+            .entries()
+            .iter()
+            .filter(|entry| matches!(entry.kind, StagedKind::File { .. }))
+            .flat_map(|entry| entry.relative_path().ancestors().skip(1))
+            .collect();
+        for (dir, mode) in dir_modes.iter().rev() {
+            if !dir.as_os_str().is_empty() && !file_dirs.contains(dir.as_path()) {
+                let path = self.root.join(dir);
+                command.push_str(&format!(" && chmod {mode:04o} -- {}", quote(&path)));
+            }
+        }
+        debug!("scp mkdir {} dirs", dir_modes.len());
+        self.run(&command).await?;
+
+        let mut child = self
+            .ssh(&format!("scp -r -p -t -- {}", quote(&self.root)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut session = ScpSession {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+        };
+        let mut stderr = child.stderr.take().unwrap();
+
+        let upload = async move {
+            session.ack().await?;
+
+            // scp walks into and out of directories with D and E records, and
+            // staged entries come parents-first, so keep track of where in the
+            // tree the remote end currently is.
+            let mut current: Vec<OsString> = vec![];
+            for entry in stage.entries() {
+                let StagedKind::File { len } = entry.kind else {
+                    continue;
+                };
+                let relative_path = entry.relative_path();
+                let Some(file_name) = relative_path.file_name() else {
+                    continue;
+                };
+                let parent: Vec<OsString> = relative_path
+                    .parent()
+                    .map(|parent| parent.iter().map(|c| c.to_os_string()).collect())
+                    .unwrap_or_default();
+
+                let common = current
+                    .iter()
+                    .zip(&parent)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                while current.len() > common {
+                    session.send(b"E\n").await?;
+                    current.pop();
+                }
+                for component in &parent[common..] {
+                    current.push(component.clone());
+                    let dir: PathBuf = current.iter().collect();
+                    let mode = dir_modes.get(&dir).copied().unwrap_or(0o755);
+                    let record = format!("D{mode:04o} 0 {}\n", component.to_string_lossy());
+                    session.send(record.as_bytes()).await?;
+                }
+
+                debug!("scp upload {}", relative_path.display());
+                let mtime = entry.modified.duration_since(UNIX_EPOCH)?.as_secs();
+                session
+                    .send(format!("T{mtime} 0 {mtime} 0\n").as_bytes())
+                    .await?;
+                let record = format!(
+                    "C{:04o} {len} {}\n",
+                    entry.mode,
+                    file_name.to_string_lossy()
+                );
+                session.send(record.as_bytes()).await?;
+                let mut file = stage.open(entry).await?.take(len);
+                let written = tokio::io::copy(&mut file, &mut session.stdin).await?;
+                if written != len {
+                    return Err(eyre!(
+                        "{} changed size while uploading",
+                        relative_path.display()
+                    ));
+                }
+                session.send(b"\0").await?;
+            }
+            while current.pop().is_some() {
+                session.send(b"E\n").await?;
+            }
+
+            // the session is dropped here on success or failure, and closing
+            // its stdin is what tells the remote scp to finish
+            Ok::<_, eyre::Report>(())
+        };
+        // ssh's stderr has to be drained while uploading, or a chatty remote
+        // fills the pipe and stops reading from stdin
+        let read_errors = async {
+            let mut errors = String::new();
+            stderr.read_to_string(&mut errors).await?;
+            Ok::<_, eyre::Report>(errors)
+        };
+        let (result, errors) = tokio::join!(upload, read_errors);
+        let errors = errors?;
+        let status = child.wait().await?;
+        result.map_err(|e| eyre!("scp to {} failed: {e} {}", self.destination, errors.trim()))?;
+        if !status.success() {
+            return Err(eyre!(
+                "scp to {} failed ({status}): {}",
+                self.destination,
+                errors.trim()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+struct ScpSession {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl ScpSession {
+    async fn send(&mut self, record: &[u8]) -> Result<()> {
+        self.stdin.write_all(record).await?;
+        self.stdin.flush().await?;
+        self.ack().await
+    }
+
+    /// The remote end answers every record with a 0 byte, or a 1 (warning)
+    /// or 2 (fatal) followed by a message.
+    async fn ack(&mut self) -> Result<()> {
+        match self.stdout.read_u8().await? {
+            0 => Ok(()),
+            _ => {
+                let mut message = String::new();
+                self.stdout.read_line(&mut message).await?;
+                Err(eyre!("{}", message.trim()))
+            }
+        }
+    }
+}
+
+/// Single-quotes a path for the remote shell.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use floppy_disk::mem::MemPermissions;
+    use floppy_disk::prelude::*;
+
+    use super::*;
+    use crate::DiskDrive;
+
+    /// Stands in for `ssh` by running the remote command locally, after
+    /// writing more to stderr than fits in a pipe.
+    const FAKE_SSH: &str = r#"#!/bin/sh
+for arg; do command=$arg; done
+head -c 200000 /dev/zero | tr '\0' e >&2
+exec sh -c "$command"
+"#;
+
+    #[tokio::test]
+    async fn uploads_while_the_remote_is_chatty() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("disk-drive-scp-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir)?;
+        let ssh = dir.join("ssh");
+        std::fs::write(&ssh, FAKE_SSH)?;
+        std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755))?;
+        let root = dir.join("root");
+
+        let src = MemFloppyDisk::new();
+        src.create_dir_all("/a/b").await?;
+        src.write("/a/b/f.txt", "hello").await?;
+        src.set_permissions("/a/b/f.txt", MemPermissions::from_mode(0o640))
+            .await?;
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        src.write("/a/big.bin", &big).await?;
+        src.symlink(PathBuf::from("b/f.txt"), PathBuf::from("/a/link"))
+            .await?;
+        src.create_dir_all("/empty/inner").await?;
+        src.set_permissions("/empty", MemPermissions::from_mode(0o700))
+            .await?;
+        src.set_permissions("/empty/inner", MemPermissions::from_mode(0o500))
+            .await?;
+        src.create_dir_all("/links").await?;
+        src.symlink(PathBuf::from("../a"), PathBuf::from("/links/a"))
+            .await?;
+        src.set_permissions("/links", MemPermissions::from_mode(0o711))
+            .await?;
+        src.set_permissions("/a/b", MemPermissions::from_mode(0o750))
+            .await?;
+
+        let dest = ScpFloppyDisk::new(ScpSink::new("fake", &root).ssh_program(&ssh));
+        let copied = tokio::time::timeout(
+            Duration::from_secs(30),
+            DiskDrive::copy_between(&src, &dest),
+        )
+        .await;
+
+        let check = || -> Result<()> {
+            copied??;
+            assert_eq!(std::fs::read(root.join("a/b/f.txt"))?, b"hello");
+            let mode = std::fs::metadata(root.join("a/b/f.txt"))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o640);
+            assert_eq!(std::fs::read(root.join("a/big.bin"))?, big);
+            assert_eq!(
+                std::fs::read_link(root.join("a/link"))?,
+                PathBuf::from("b/f.txt")
+            );
+            assert_eq!(
+                std::fs::read_link(root.join("links/a"))?,
+                PathBuf::from("../a")
+            );
+            for (dir, expected) in [
+                ("a/b", 0o750),
+                ("empty", 0o700),
+                ("empty/inner", 0o500),
+                ("links", 0o711),
+            ] {
+                let mode = std::fs::metadata(root.join(dir))?.permissions().mode();
+                assert_eq!(mode & 0o777, expected, "{dir}");
+            }
+            Ok(())
+        };
+        let result = check();
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+}
@@ -0,0 +1,745 @@
+//! Disks that can't be written to file-by-file, so they collect everything
+//! and hand it to a [`Sink`] once the copy is finished.
+//!
+//! The tree itself (directories, symlinks, modes, and ownership) is kept in
+//! memory, but file contents are spilled to a private temporary directory as
+//! they're written, so staging a large copy doesn't need it all in RAM.
+
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use floppy_disk::mem::{
+    MemDirBuilder, MemDirEntry, MemFile, MemFileType, MemMetadata, MemOpenOptions, MemPermissions,
+    MemReadDir,
+};
+use floppy_disk::prelude::*;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::sync::OnceCell;
+use tracing::{trace, warn};
+
+use crate::{CopySummary, FloppyDiskFinalize};
+
+/// Where a [`StagedFloppyDisk`] sends its contents on finalize.
+#[async_trait::async_trait]
+pub trait Sink: Debug + Send + Sync + Unpin {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> eyre::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct StagedFloppyDisk<S: Sink> {
+    staged: MemFloppyDisk,
+    /// Where file contents go, at the same paths as in `staged`. Only created
+    /// once the first file is written.
+    spill: PathBuf,
+    spill_created: OnceCell<()>,
+    sink: S,
+}
+
+impl<S: Sink> StagedFloppyDisk<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            staged: MemFloppyDisk::new(),
+            spill: std::env::temp_dir()
+                .join(format!("disk-drive-spill-{:016x}", rand::random::<u64>())),
+            spill_created: OnceCell::new(),
+            sink,
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    async fn clear(&self) -> Result<()> {
+        // the staged tree enforces modes, so read-only directories have to
+        // be opened up before anything in them can be removed
+        let mut dirs = vec![PathBuf::from("/")];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = self.staged.read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    let path = entry.path();
+                    self.staged
+                        .set_permissions(&path, MemPermissions::from_mode(0o700))
+                        .await?;
+                    dirs.push(path);
+                }
+            }
+        }
+
+        let mut entries = self.staged.read_dir("/").await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                self.staged.remove_dir_all(path).await?;
+            } else {
+                self.staged.remove_file(path).await?;
+            }
+        }
+
+        if self.spill_created.initialized() {
+            let mut entries = tokio::fs::read_dir(&self.spill).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                remove_contents(&entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the contents of the staged file at `path` are spilled to.
+    /// Symlinks are resolved through the staged tree, so every path to a file
+    /// ends up at the same place.
+    async fn contents_path(&self, path: &Path) -> Result<PathBuf> {
+        let path = self.staged.canonicalize(path).await?;
+        Ok(self.spill.join(path.strip_prefix("/").unwrap_or(&path)))
+    }
+
+    /// Like [`Self::contents_path`], but without following `path` itself if
+    /// it's a symlink, for removing and renaming.
+    async fn entry_contents_path(&self, path: &Path) -> Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                let parent = if parent.as_os_str().is_empty() {
+                    Path::new("/")
+                } else {
+                    parent
+                };
+                Ok(self.contents_path(parent).await?.join(name))
+            }
+            _ => self.contents_path(path).await,
+        }
+    }
+
+    /// [`Self::contents_path`] for a file that's about to be written, with
+    /// its parent directories created in the spill.
+    async fn create_contents_path(&self, path: &Path) -> Result<PathBuf> {
+        self.spill_created
+            .get_or_try_init(|| async {
+                tokio::fs::DirBuilder::new()
+                    .mode(0o700)
+                    .create(&self.spill)
+                    .await
+            })
+            .await?;
+        let contents = self.contents_path(path).await?;
+        if let Some(parent) = contents.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(contents)
+    }
+}
+
+impl<S: Sink> Drop for StagedFloppyDisk<S> {
+    fn drop(&mut self) {
+        if self.spill_created.initialized() {
+            if let Err(e) = std::fs::remove_dir_all(&self.spill) {
+                warn!("failed to remove {}: {e}", self.spill.display());
+            }
+        }
+    }
+}
+
+/// Removes spilled contents, which may be a file or a whole directory, or
+/// may never have been written at all.
+async fn remove_contents(path: &Path) -> Result<()> {
+    let result = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Sink> FloppyDiskFinalize for StagedFloppyDisk<S> {
+    async fn finalize(&self, summary: &mut CopySummary) -> eyre::Result<()> {
+        // the stage is cleared even if the flush fails, or the next copy
+        // would flush this one's leftovers along with it
+        let flushed = async {
+            let stage = Stage::collect(&self.staged, &self.spill).await?;
+            trace!("flushing {} staged entries", stage.entries().len());
+            self.sink.flush(&stage, summary).await
+        }
+        .await;
+        let cleared = self.clear().await;
+        flushed?;
+        cleared?;
+        Ok(())
+    }
+
+    async fn abort(&self) -> eyre::Result<()> {
+        self.clear().await?;
+        Ok(())
+    }
+}
+
+/// A snapshot of everything a [`StagedFloppyDisk`] has been given, in path
+/// order, so parents always come before their children.
+#[derive(Debug)]
+pub struct Stage<'a> {
+    spill: &'a Path,
+    entries: Vec<StagedEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StagedEntry {
+    pub path: PathBuf,
+    pub kind: StagedKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StagedKind {
+    Dir,
+    File { len: u64 },
+    Symlink { target: PathBuf },
+}
+
+impl<'a> Stage<'a> {
+    async fn collect(disk: &MemFloppyDisk, spill: &'a Path) -> Result<Stage<'a>> {
+        let mut entries = vec![];
+        for path in nyoom::walk_ordered(disk, "/").await? {
+            let metadata = disk.symlink_metadata(&path).await?;
+            let kind = if metadata.is_symlink() {
+                StagedKind::Symlink {
+                    target: disk.read_link(&path).await?,
+                }
+            } else if metadata.is_dir() {
+                StagedKind::Dir
+            } else {
+                let contents = spill.join(path.strip_prefix("/").unwrap_or(&path));
+                StagedKind::File {
+                    len: tokio::fs::metadata(contents).await?.len(),
+                }
+            };
+            entries.push(StagedEntry {
+                path,
+                kind,
+                mode: metadata.permissions().mode() & 0o7777,
+                uid: metadata.uid()?,
+                gid: metadata.gid()?,
+                modified: metadata.modified()?,
+            });
+        }
+
+        Ok(Self { spill, entries })
+    }
+
+    pub fn entries(&self) -> &[StagedEntry] {
+        &self.entries
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| match entry.kind {
+                StagedKind::File { len } => len,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Where a staged file's contents are on the local filesystem, for
+    /// handing to tools that read from a path. `None` if `entry` isn't a
+    /// file.
+    pub fn contents_path(&self, entry: &StagedEntry) -> Option<PathBuf> {
+        match entry.kind {
+            StagedKind::File { .. } => Some(self.spill.join(entry.relative_path())),
+            _ => None,
+        }
+    }
+
+    /// Opens a staged file's contents for reading.
+    pub async fn open(&self, entry: &StagedEntry) -> Result<tokio::fs::File> {
+        let path = self.contents_path(entry).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} isn't a file", entry.path.display()),
+            )
+        })?;
+        tokio::fs::File::open(path).await
+    }
+}
+
+impl StagedEntry {
+    /// The staged path without its leading `/`, for joining onto a remote
+    /// root.
+    pub fn relative_path(&self) -> &Path {
+        self.path.strip_prefix("/").unwrap_or(&self.path)
+    }
+}
+
+#[async_trait::async_trait]
+impl<'a, S: Sink + 'a> FloppyDisk<'a> for StagedFloppyDisk<S> {
+    type DirBuilder = MemDirBuilder<'a>;
+    type DirEntry = StagedDirEntry;
+    type File = StagedFile;
+    type FileType = MemFileType;
+    type Metadata = StagedMetadata;
+    type OpenOptions = StagedOpenOptions;
+    type Permissions = MemPermissions;
+    type ReadDir = StagedReadDir;
+
+    async fn canonicalize<P: AsRef<Path> + Send>(&self, path: P) -> Result<PathBuf> {
+        self.staged.canonicalize(path).await
+    }
+
+    async fn copy<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<u64> {
+        self.staged.copy(from.as_ref(), to.as_ref()).await?;
+        let from = self.contents_path(from.as_ref()).await?;
+        let to = self.create_contents_path(to.as_ref()).await?;
+        tokio::fs::copy(from, to).await
+    }
+
+    async fn create_dir<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        self.staged.create_dir(path).await
+    }
+
+    async fn create_dir_all<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        self.staged.create_dir_all(path).await
+    }
+
+    async fn hard_link<P: AsRef<Path> + Send>(&self, _src: P, _dst: P) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "hard links can't be staged",
+        ))
+    }
+
+    async fn metadata<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+        let metadata = self.staged.metadata(path.as_ref()).await?;
+        StagedMetadata::new(self, path.as_ref(), metadata).await
+    }
+
+    async fn read<P: AsRef<Path> + Send>(&self, path: P) -> Result<Vec<u8>> {
+        // the staged tree only holds an empty placeholder, but it still knows
+        // whether reading is allowed
+        self.staged.read(path.as_ref()).await?;
+        tokio::fs::read(self.contents_path(path.as_ref()).await?).await
+    }
+
+    async fn read_dir<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::ReadDir> {
+        let entries = self.staged.read_dir(path.as_ref()).await?;
+        Ok(StagedReadDir {
+            entries,
+            contents: self.contents_path(path.as_ref()).await?,
+        })
+    }
+
+    async fn read_link<P: AsRef<Path> + Send>(&self, path: P) -> Result<PathBuf> {
+        self.staged.read_link(path).await
+    }
+
+    async fn read_to_string<P: AsRef<Path> + Send>(&self, path: P) -> Result<String> {
+        String::from_utf8(self.read(path).await?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    async fn remove_dir<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let contents = self.entry_contents_path(path.as_ref()).await?;
+        self.staged.remove_dir(path).await?;
+        remove_contents(&contents).await
+    }
+
+    async fn remove_dir_all<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let contents = self.entry_contents_path(path.as_ref()).await?;
+        self.staged.remove_dir_all(path).await?;
+        remove_contents(&contents).await
+    }
+
+    async fn remove_file<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let contents = self.entry_contents_path(path.as_ref()).await?;
+        self.staged.remove_file(path).await?;
+        remove_contents(&contents).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<()> {
+        let from_contents = self.entry_contents_path(from.as_ref()).await?;
+        self.staged.rename(from.as_ref(), to.as_ref()).await?;
+        let to_contents = self.entry_contents_path(to.as_ref()).await?;
+        // whatever was at `to` has been replaced in the staged tree, so its
+        // contents have to go too
+        remove_contents(&to_contents).await?;
+        match tokio::fs::symlink_metadata(&from_contents).await {
+            Ok(_) => {
+                if let Some(parent) = to_contents.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(from_contents, to_contents).await
+            }
+            // symlinks and directories without files have nothing spilled
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_permissions<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        perm: Self::Permissions,
+    ) -> Result<()> {
+        self.staged.set_permissions(path, perm).await
+    }
+
+    async fn symlink<P: AsRef<Path> + Send>(&self, src: P, dst: P) -> Result<()> {
+        self.staged.symlink(src, dst).await
+    }
+
+    async fn symlink_metadata<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+        let metadata = self.staged.symlink_metadata(path.as_ref()).await?;
+        StagedMetadata::new(self, path.as_ref(), metadata).await
+    }
+
+    async fn try_exists<P: AsRef<Path> + Send>(&self, path: P) -> Result<bool> {
+        self.staged.try_exists(path).await
+    }
+
+    async fn write<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        contents: impl AsRef<[u8]> + Send,
+    ) -> Result<()> {
+        self.staged.write(path.as_ref(), []).await?;
+        let path = self.create_contents_path(path.as_ref()).await?;
+        tokio::fs::write(path, contents).await
+    }
+
+    fn new_dir_builder(&'a self) -> Self::DirBuilder {
+        self.staged.new_dir_builder()
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Sink> FloppyDiskUnixExt for StagedFloppyDisk<S> {
+    async fn chown<P: Into<PathBuf> + Send>(&self, path: P, uid: u32, gid: u32) -> Result<()> {
+        self.staged.chown(path, uid, gid).await
+    }
+}
+
+/// A staged file. Metadata lives on the placeholder in the staged tree, and
+/// the bytes in the spilled file.
+#[derive(Debug)]
+pub struct StagedFile {
+    placeholder: MemFile,
+    contents: tokio::fs::File,
+}
+
+#[async_trait::async_trait]
+impl<'a, S: Sink + 'a> FloppyFile<'a, StagedFloppyDisk<S>> for StagedFile {
+    async fn sync_all(&mut self) -> Result<()> {
+        self.contents.sync_all().await
+    }
+
+    async fn sync_data(&mut self) -> Result<()> {
+        self.contents.sync_data().await
+    }
+
+    async fn set_len(&mut self, size: u64) -> Result<()> {
+        self.contents.set_len(size).await
+    }
+
+    async fn metadata(&self) -> Result<StagedMetadata> {
+        Ok(StagedMetadata {
+            metadata: FloppyFile::<MemFloppyDisk>::metadata(&self.placeholder).await?,
+            len: self.contents.metadata().await?.len(),
+        })
+    }
+
+    async fn try_clone(&'a self) -> Result<Box<StagedFile>> {
+        let placeholder = FloppyFile::<MemFloppyDisk>::try_clone(&self.placeholder).await?;
+        Ok(Box::new(StagedFile {
+            placeholder: *placeholder,
+            contents: self.contents.try_clone().await?,
+        }))
+    }
+
+    async fn set_permissions(&self, perm: MemPermissions) -> Result<()> {
+        FloppyFile::<MemFloppyDisk>::set_permissions(&self.placeholder, perm).await
+    }
+
+    async fn permissions(&self) -> Result<MemPermissions> {
+        FloppyFile::<MemFloppyDisk>::permissions(&self.placeholder).await
+    }
+}
+
+impl AsyncSeek for StagedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: std::io::SeekFrom) -> Result<()> {
+        Pin::new(&mut self.contents).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<u64>> {
+        Pin::new(&mut self.contents).poll_complete(cx)
+    }
+}
+
+impl AsyncRead for StagedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.contents).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StagedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.contents).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.contents).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.contents).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+pub struct StagedMetadata {
+    metadata: MemMetadata,
+    /// From the spilled contents, since the staged placeholder is empty.
+    len: u64,
+}
+
+impl StagedMetadata {
+    async fn new<S: Sink>(
+        disk: &StagedFloppyDisk<S>,
+        path: &Path,
+        metadata: MemMetadata,
+    ) -> Result<Self> {
+        let len = if FloppyMetadata::<MemFloppyDisk>::is_file(&metadata) {
+            tokio::fs::metadata(disk.contents_path(path).await?)
+                .await?
+                .len()
+        } else {
+            FloppyMetadata::<MemFloppyDisk>::len(&metadata)
+        };
+        Ok(Self { metadata, len })
+    }
+}
+
+impl<'a, S: Sink + 'a> FloppyMetadata<'a, StagedFloppyDisk<S>> for StagedMetadata {
+    fn file_type(&self) -> MemFileType {
+        FloppyMetadata::<MemFloppyDisk>::file_type(&self.metadata)
+    }
+
+    fn is_dir(&self) -> bool {
+        FloppyMetadata::<MemFloppyDisk>::is_dir(&self.metadata)
+    }
+
+    fn is_file(&self) -> bool {
+        FloppyMetadata::<MemFloppyDisk>::is_file(&self.metadata)
+    }
+
+    fn is_symlink(&self) -> bool {
+        FloppyMetadata::<MemFloppyDisk>::is_symlink(&self.metadata)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn permissions(&self) -> MemPermissions {
+        FloppyMetadata::<MemFloppyDisk>::permissions(&self.metadata)
+    }
+
+    fn modified(&self) -> Result<SystemTime> {
+        FloppyMetadata::<MemFloppyDisk>::modified(&self.metadata)
+    }
+
+    fn accessed(&self) -> Result<SystemTime> {
+        FloppyMetadata::<MemFloppyDisk>::accessed(&self.metadata)
+    }
+
+    fn created(&self) -> Result<SystemTime> {
+        FloppyMetadata::<MemFloppyDisk>::created(&self.metadata)
+    }
+}
+
+impl FloppyUnixMetadata for StagedMetadata {
+    fn uid(&self) -> Result<u32> {
+        self.metadata.uid()
+    }
+
+    fn gid(&self) -> Result<u32> {
+        self.metadata.gid()
+    }
+}
+
+#[derive(Debug)]
+pub struct StagedReadDir {
+    entries: MemReadDir,
+    /// The spilled directory the entries' contents are in.
+    contents: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl<'a, S: Sink + 'a> FloppyReadDir<'a, StagedFloppyDisk<S>> for StagedReadDir {
+    async fn next_entry(&mut self) -> Result<Option<StagedDirEntry>> {
+        let Some(entry) = FloppyReadDir::<MemFloppyDisk>::next_entry(&mut self.entries).await?
+        else {
+            return Ok(None);
+        };
+        let contents = self
+            .contents
+            .join(FloppyDirEntry::<MemFloppyDisk>::file_name(&entry));
+        Ok(Some(StagedDirEntry { entry, contents }))
+    }
+}
+
+#[derive(Debug)]
+pub struct StagedDirEntry {
+    entry: MemDirEntry,
+    contents: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl<'a, S: Sink + 'a> FloppyDirEntry<'a, StagedFloppyDisk<S>> for StagedDirEntry {
+    fn path(&self) -> PathBuf {
+        FloppyDirEntry::<MemFloppyDisk>::path(&self.entry)
+    }
+
+    fn file_name(&self) -> OsString {
+        FloppyDirEntry::<MemFloppyDisk>::file_name(&self.entry)
+    }
+
+    async fn metadata(&self) -> Result<StagedMetadata> {
+        let metadata = FloppyDirEntry::<MemFloppyDisk>::metadata(&self.entry).await?;
+        let len = if FloppyMetadata::<MemFloppyDisk>::is_file(&metadata) {
+            tokio::fs::metadata(&self.contents).await?.len()
+        } else {
+            FloppyMetadata::<MemFloppyDisk>::len(&metadata)
+        };
+        Ok(StagedMetadata { metadata, len })
+    }
+
+    async fn file_type(&self) -> Result<MemFileType> {
+        FloppyDirEntry::<MemFloppyDisk>::file_type(&self.entry).await
+    }
+
+    #[cfg(unix)]
+    fn ino(&self) -> u64 {
+        FloppyDirEntry::<MemFloppyDisk>::ino(&self.entry)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StagedOpenOptions {
+    options: MemOpenOptions,
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+#[async_trait::async_trait]
+impl<'a, S: Sink + 'a> FloppyOpenOptions<'a, StagedFloppyDisk<S>> for StagedOpenOptions {
+    fn new() -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::new(),
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+        }
+    }
+
+    fn read(self, read: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::read(self.options, read),
+            read,
+            ..self
+        }
+    }
+
+    fn write(self, write: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::write(self.options, write),
+            write,
+            ..self
+        }
+    }
+
+    fn append(self, append: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::append(self.options, append),
+            append,
+            ..self
+        }
+    }
+
+    fn truncate(self, truncate: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::truncate(self.options, truncate),
+            truncate,
+            ..self
+        }
+    }
+
+    fn create(self, create: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::create(self.options, create),
+            create,
+            ..self
+        }
+    }
+
+    fn create_new(self, create_new: bool) -> Self {
+        Self {
+            options: FloppyOpenOptions::<MemFloppyDisk>::create_new(self.options, create_new),
+            create_new,
+            ..self
+        }
+    }
+
+    async fn open<P: AsRef<Path> + Send>(
+        &self,
+        disk: &'a StagedFloppyDisk<S>,
+        path: P,
+    ) -> Result<StagedFile> {
+        let placeholder =
+            FloppyOpenOptions::<MemFloppyDisk>::open(&self.options, &disk.staged, path.as_ref())
+                .await?;
+        let creating = self.create || self.create_new;
+        let contents_path = if creating {
+            disk.create_contents_path(path.as_ref()).await?
+        } else {
+            disk.contents_path(path.as_ref()).await?
+        };
+        // the staged tree has already checked create_new, and the
+        // placeholder exists now, so the spilled file only needs creating
+        let contents = tokio::fs::OpenOptions::new()
+            .read(self.read)
+            .write(self.write)
+            .append(self.append)
+            .truncate(self.truncate)
+            .create(creating)
+            .mode(0o600)
+            .open(contents_path)
+            .await?;
+        Ok(StagedFile {
+            placeholder,
+            contents,
+        })
+    }
+}
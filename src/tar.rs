@@ -0,0 +1,292 @@
+//! A minimal streaming tar writer: ustar headers, with PAX records for
+//! anything that doesn't fit in one.
+
+use std::time::UNIX_EPOCH;
+
+use eyre::{eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::staged::{StagedEntry, StagedKind};
+
+const BLOCK: usize = 512;
+
+pub(crate) struct TarWriter<W: AsyncWrite + Unpin> {
+    out: W,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    pub(crate) fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Writes one entry. `data` is only read for files, and has to have the
+    /// entry's staged length.
+    pub(crate) async fn append<R: AsyncRead + Unpin>(
+        &mut self,
+        entry: &StagedEntry,
+        data: R,
+    ) -> Result<()> {
+        let mut path = entry.relative_path().to_string_lossy().to_string();
+        let (typeflag, size, link) = match &entry.kind {
+            StagedKind::Dir => {
+                path.push('/');
+                (b'5', 0, String::new())
+            }
+            StagedKind::File { len } => (b'0', *len, String::new()),
+            StagedKind::Symlink { target } => (b'2', 0, target.to_string_lossy().to_string()),
+        };
+        let mtime = entry
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut pax = vec![];
+        if path.len() > 100 {
+            pax.push(pax_record("path", &path));
+        }
+        if link.len() > 100 {
+            pax.push(pax_record("linkpath", &link));
+        }
+        if size > 0o77777777777 {
+            pax.push(pax_record("size", &size.to_string()));
+        }
+        // uid and gid only get seven octal digits
+        if entry.uid > 0o7777777 {
+            pax.push(pax_record("uid", &entry.uid.to_string()));
+        }
+        if entry.gid > 0o7777777 {
+            pax.push(pax_record("gid", &entry.gid.to_string()));
+        }
+        if !pax.is_empty() {
+            let records = pax.concat();
+            let header = Header {
+                path: "PaxHeader",
+                typeflag: b'x',
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: records.len() as u64,
+                mtime,
+                link: "",
+            };
+            self.out.write_all(&header.encode()).await?;
+            self.out.write_all(&records).await?;
+            self.pad(records.len() as u64).await?;
+        }
+
+        let header = Header {
+            path: &path,
+            typeflag,
+            mode: entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            size,
+            mtime,
+            link: &link,
+        };
+        self.out.write_all(&header.encode()).await?;
+        if typeflag == b'0' {
+            let written = tokio::io::copy(&mut data.take(size), &mut self.out).await?;
+            if written != size {
+                return Err(eyre!(
+                    "{} changed size while archiving",
+                    entry.path.display()
+                ));
+            }
+            self.pad(size).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the two empty blocks that end an archive.
+    pub(crate) async fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0; BLOCK * 2]).await?;
+        self.out.flush().await?;
+        Ok(self.out)
+    }
+
+    /// Pads `written` bytes out to a whole block.
+    async fn pad(&mut self, written: u64) -> Result<()> {
+        let padding = (BLOCK - (written % BLOCK as u64) as usize) % BLOCK;
+        self.out.write_all(&[0; BLOCK][..padding]).await?;
+        Ok(())
+    }
+}
+
+struct Header<'a> {
+    path: &'a str,
+    typeflag: u8,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    link: &'a str,
+}
+
+impl Header<'_> {
+    fn encode(&self) -> [u8; BLOCK] {
+        let mut header = [0; BLOCK];
+        // too-long names and links are carried by a PAX header instead, so
+        // truncating here is fine
+        copy_truncated(&mut header[0..100], self.path.as_bytes());
+        octal(&mut header[100..108], self.mode as u64);
+        octal(&mut header[108..116], self.uid as u64);
+        octal(&mut header[116..124], self.gid as u64);
+        octal(&mut header[124..136], self.size);
+        octal(&mut header[136..148], self.mtime);
+        header[156] = self.typeflag;
+        copy_truncated(&mut header[157..257], self.link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // the checksum is calculated with its own field filled with spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+        header
+    }
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Zero-padded octal, NUL-terminated, filling the field. Values too big for
+/// the field are clamped, and carried by a PAX header instead.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let max = (1u64 << (3 * digits as u32)) - 1;
+    let formatted = format!("{:0digits$o}", value.min(max));
+    field[..digits].copy_from_slice(formatted.as_bytes());
+    field[digits] = 0;
+}
+
+/// A `<len> <key>=<value>\n` record, where `len` counts the whole record
+/// including itself.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = format!(" {key}={value}\n");
+    let mut len = rest.len() + 1;
+    while len.to_string().len() + rest.len() > len {
+        len += 1;
+    }
+    format!("{len}{rest}").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry(path: &str, kind: StagedKind, uid: u32, gid: u32) -> StagedEntry {
+        StagedEntry {
+            path: path.into(),
+            kind,
+            mode: 0o640,
+            uid,
+            gid,
+            modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    /// Runs the system tar over `archive`.
+    fn tar(archive: &[u8], args: &[&str]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("disk-drive-tar-{:016x}.tar", rand::random::<u64>()));
+        std::fs::write(&path, archive).unwrap();
+        let output = Command::new("tar")
+            .env("TZ", "UTC")
+            .args(args)
+            .arg("-f")
+            .arg(&path)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            output.status.success(),
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_archives_tar_can_read() -> Result<()> {
+        let long = format!("/{}/file.txt", "d".repeat(120));
+        let mut writer = TarWriter::new(vec![]);
+        writer
+            .append(
+                &entry("/dir", StagedKind::Dir, 1000, 1000),
+                tokio::io::empty(),
+            )
+            .await?;
+        writer
+            .append(
+                &entry("/dir/small.txt", StagedKind::File { len: 5 }, 1000, 100),
+                &b"hello"[..],
+            )
+            .await?;
+        writer
+            .append(
+                &entry(&long, StagedKind::File { len: 3 }, 3_000_000, 4_000_000),
+                &b"big"[..],
+            )
+            .await?;
+        let target = PathBuf::from(format!("../{}", "t".repeat(150)));
+        writer
+            .append(
+                &entry("/link", StagedKind::Symlink { target }, 0, 0),
+                tokio::io::empty(),
+            )
+            .await?;
+        let archive = writer.finish().await?;
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let listing = tar(&archive, &["-tv", "--numeric-owner"]);
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines.len(), 4, "{listing}");
+        assert!(lines[0].starts_with("drw-r----- 1000/1000 "));
+        assert!(lines[0].ends_with(" dir/"));
+        assert!(lines[1].starts_with("-rw-r----- 1000/100 "));
+        assert!(lines[1].ends_with(" dir/small.txt"));
+        // ownership past what ustar can hold comes through the PAX header
+        assert!(lines[2].starts_with("-rw-r----- 3000000/4000000 "));
+        assert!(lines[2].ends_with(&long[1..]));
+        assert!(lines[3].ends_with(&format!(" link -> ../{}", "t".repeat(150))));
+        assert!(lines[0].contains(" 2023-11-14 "));
+
+        assert_eq!(tar(&archive, &["-xO", "dir/small.txt"]), "hello");
+        assert_eq!(tar(&archive, &["-xO", &long[1..]]), "big");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_files_that_changed_size() -> Result<()> {
+        let mut writer = TarWriter::new(vec![]);
+        let error = writer
+            .append(
+                &entry("/short", StagedKind::File { len: 10 }, 0, 0),
+                &b"abc"[..],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "/short changed size while archiving");
+        Ok(())
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        assert_eq!(pax_record("uid", "3000000"), b"15 uid=3000000\n");
+        // 98 bytes after the length, so the length needs three digits
+        let record = pax_record("path", &"p".repeat(91));
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with(b"101 path="));
+    }
+}
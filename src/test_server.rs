@@ -0,0 +1,98 @@
+//! A tiny HTTP server on localhost for testing the backends that talk HTTP.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) target: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Answers every request with the `(status, body)` that `respond`
+/// returns. Returns the server's base URL, and the requests it has
+/// received so far.
+pub(crate) async fn serve<F>(respond: F) -> (String, Arc<Mutex<Vec<Request>>>)
+where
+    F: Fn(&Request) -> (u16, Vec<u8>) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let respond = Arc::new(respond);
+
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let respond = respond.clone();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let target = parts.next().unwrap_or_default().to_string();
+
+                let mut headers = vec![];
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(':') else {
+                        break;
+                    };
+                    headers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+                let mut request = Request {
+                    method,
+                    target,
+                    headers,
+                    body: vec![],
+                };
+
+                if request
+                    .header("expect")
+                    .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+                {
+                    stream
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+                let len = request
+                    .header("content-length")
+                    .and_then(|len| len.parse().ok())
+                    .unwrap_or(0);
+                request.body = vec![0; len];
+                stream.read_exact(&mut request.body).await.unwrap();
+
+                let (status, body) = respond(&request);
+                seen.lock().unwrap().push(request);
+                let head = format!(
+                    "HTTP/1.1 {status} Test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let stream = stream.get_mut();
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+                stream.shutdown().await.unwrap();
+            });
+        }
+    });
+
+    (url, requests)
+}
@@ -0,0 +1,65 @@
+//! Calendar dates, for the formats that want them written out.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `(year, month, day, hour, minute, second)` in UTC. Times before the epoch
+/// are taken as the epoch.
+pub(crate) fn civil(time: SystemTime) -> (u64, u64, u64, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+/// RFC 3339 in UTC, with as many fractional digits as it takes, the way Go
+/// marshals times into JSON.
+#[cfg(feature = "restic")]
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil(time);
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let fraction = if nanos == 0 {
+        String::new()
+    } else {
+        format!(".{nanos:09}").trim_end_matches('0').to_string()
+    };
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}{fraction}Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn splits_dates() {
+        let time = UNIX_EPOCH + Duration::new(951_827_696, 120_000_000);
+        assert_eq!(civil(time), (2000, 2, 29, 12, 34, 56));
+        assert_eq!(civil(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0));
+    }
+
+    #[cfg(feature = "restic")]
+    #[test]
+    fn formats_rfc3339() {
+        let time = UNIX_EPOCH + Duration::new(951_827_696, 120_000_000);
+        assert_eq!(rfc3339(time), "2000-02-29T12:34:56.12Z");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+}
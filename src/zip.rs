@@ -0,0 +1,213 @@
+//! A minimal in-memory zip writer. Entries are stored uncompressed.
+
+use eyre::{eyre, Result};
+
+pub(crate) struct ZipWriter {
+    out: Vec<u8>,
+    central: Vec<u8>,
+    entries: u16,
+    /// MS-DOS `(time, date)` stamped on every entry.
+    modified: (u16, u16),
+}
+
+impl ZipWriter {
+    pub(crate) fn new(modified: (u16, u16)) -> Self {
+        Self {
+            out: vec![],
+            central: vec![],
+            entries: 0,
+            modified,
+        }
+    }
+
+    /// How big the archive is so far, not counting the central directory.
+    pub(crate) fn len(&self) -> usize {
+        self.out.len()
+    }
+
+    pub(crate) fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let offset = u32::try_from(self.out.len())
+            .map_err(|_| eyre!("zip archive is too big without zip64"))?;
+        let size = u32::try_from(data.len()).map_err(|_| eyre!("zip entry {name} is too big"))?;
+        self.entries = self
+            .entries
+            .checked_add(1)
+            .ok_or_else(|| eyre!("too many zip entries without zip64"))?;
+        let crc = crc32(data);
+        let (time, date) = self.modified;
+
+        // local file header
+        self.out.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        self.out.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        self.out.extend_from_slice(&0x0800u16.to_le_bytes()); // utf-8 names
+        self.out.extend_from_slice(&0u16.to_le_bytes()); // stored
+        self.out.extend_from_slice(&time.to_le_bytes());
+        self.out.extend_from_slice(&date.to_le_bytes());
+        self.out.extend_from_slice(&crc.to_le_bytes());
+        self.out.extend_from_slice(&size.to_le_bytes()); // compressed
+        self.out.extend_from_slice(&size.to_le_bytes()); // uncompressed
+        self.out
+            .extend_from_slice(&(name.len() as u16).to_le_bytes());
+        self.out.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        self.out.extend_from_slice(name.as_bytes());
+        self.out.extend_from_slice(data);
+
+        // central directory header
+        self.central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        self.central.extend_from_slice(&0x031eu16.to_le_bytes()); // made by: unix, 3.0
+        self.central.extend_from_slice(&20u16.to_le_bytes());
+        self.central.extend_from_slice(&0x0800u16.to_le_bytes());
+        self.central.extend_from_slice(&0u16.to_le_bytes());
+        self.central.extend_from_slice(&time.to_le_bytes());
+        self.central.extend_from_slice(&date.to_le_bytes());
+        self.central.extend_from_slice(&crc.to_le_bytes());
+        self.central.extend_from_slice(&size.to_le_bytes());
+        self.central.extend_from_slice(&size.to_le_bytes());
+        self.central
+            .extend_from_slice(&(name.len() as u16).to_le_bytes());
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        self.central.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        self.central
+            .extend_from_slice(&(0o100644u32 << 16).to_le_bytes()); // external attributes
+        self.central.extend_from_slice(&offset.to_le_bytes());
+        self.central.extend_from_slice(name.as_bytes());
+
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<Vec<u8>> {
+        let offset = u32::try_from(self.out.len())
+            .map_err(|_| eyre!("zip archive is too big without zip64"))?;
+        let size = self.central.len() as u32;
+        self.out.append(&mut self.central);
+
+        // end of central directory
+        self.out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        self.out.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        self.out.extend_from_slice(&0u16.to_le_bytes()); // central directory disk
+        self.out.extend_from_slice(&self.entries.to_le_bytes());
+        self.out.extend_from_slice(&self.entries.to_le_bytes());
+        self.out.extend_from_slice(&size.to_le_bytes());
+        self.out.extend_from_slice(&offset.to_le_bytes());
+        self.out.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        Ok(self.out)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Reads back every entry of a stored zip, checking each one's CRC.
+#[cfg(test)]
+pub(crate) fn read(zip: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let u16_at = |at: usize| u16::from_le_bytes([zip[at], zip[at + 1]]) as usize;
+    let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap()) as usize;
+
+    let end = zip
+        .len()
+        .checked_sub(22)
+        .ok_or_else(|| eyre!("too short"))?;
+    if u32_at(end) != 0x06054b50 {
+        return Err(eyre!("no end of central directory"));
+    }
+    let mut central = u32_at(end + 16);
+    let mut entries = vec![];
+    for _ in 0..u16_at(end + 10) {
+        if u32_at(central) != 0x02014b50 {
+            return Err(eyre!("bad central directory header"));
+        }
+        let name_len = u16_at(central + 28);
+        let name = String::from_utf8(zip[central + 46..central + 46 + name_len].to_vec())?;
+        let local = u32_at(central + 42);
+        if u32_at(local) != 0x04034b50 || u16_at(local + 8) != 0 {
+            return Err(eyre!("bad local header for {name}"));
+        }
+        let start = local + 30 + u16_at(local + 26) + u16_at(local + 28);
+        let data = zip[start..start + u32_at(local + 18)].to_vec();
+        if crc32(&data) as usize != u32_at(central + 16) {
+            return Err(eyre!("crc mismatch in {name}"));
+        }
+        entries.push((name, data));
+        central += 46 + name_len + u16_at(central + 30) + u16_at(central + 32);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn matches_known_crcs() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+
+    #[test]
+    fn writes_archives_unzip_can_read() -> Result<()> {
+        // 2024-02-29 12:34:56
+        let mut writer = ZipWriter::new((12 << 11 | 34 << 5 | 28, 44 << 9 | 2 << 5 | 29));
+        writer.add("manifest", b"{}")?;
+        writer.add("list/a-b_c", &[0xff; 1000])?;
+        writer.add("empty", b"")?;
+        let zip = writer.finish()?;
+
+        let path =
+            std::env::temp_dir().join(format!("disk-drive-zip-{:016x}.zip", rand::random::<u64>()));
+        std::fs::write(&path, &zip)?;
+        let test = Command::new("unzip").arg("-t").arg(&path).output()?;
+        let listing = Command::new("unzip")
+            .arg("-Z")
+            .arg("-T")
+            .arg(&path)
+            .output()?;
+        let contents = Command::new("unzip")
+            .arg("-p")
+            .arg(&path)
+            .arg("manifest")
+            .output()?;
+        std::fs::remove_file(&path)?;
+        assert!(
+            test.status.success(),
+            "unzip failed: {}",
+            String::from_utf8_lossy(&test.stdout)
+        );
+        let listing = String::from_utf8(listing.stdout)?;
+        assert!(
+            listing.contains("1000 b- stor 20240229.123456 list/a-b_c"),
+            "{listing}"
+        );
+        assert_eq!(contents.stdout, b"{}");
+
+        assert_eq!(
+            read(&zip)?,
+            [
+                ("manifest".to_string(), b"{}".to_vec()),
+                ("list/a-b_c".to_string(), vec![0xff; 1000]),
+                ("empty".to_string(), vec![]),
+            ]
+        );
+        Ok(())
+    }
+}
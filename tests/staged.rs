@@ -0,0 +1,233 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use disk_drive::staged::{Sink, Stage, StagedFloppyDisk, StagedKind, StagedMetadata};
+use disk_drive::{CopySummary, DiskDrive, FloppyDiskFinalize};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Default)]
+struct RecordingSink {
+    flushes: Mutex<Vec<Vec<Recorded>>>,
+    /// Fails the next flush, the way a sink does when the remote is full.
+    fail: AtomicBool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Recorded {
+    path: PathBuf,
+    kind: StagedKind,
+    mode: u32,
+    contents: Vec<u8>,
+    contents_path: Option<PathBuf>,
+}
+
+#[async_trait::async_trait]
+impl Sink for RecordingSink {
+    async fn flush(&self, stage: &Stage<'_>, summary: &mut CopySummary) -> eyre::Result<()> {
+        if self.fail.swap(false, Ordering::SeqCst) {
+            return Err(eyre::eyre!("out of space"));
+        }
+        let mut recorded = vec![];
+        for entry in stage.entries() {
+            let mut contents = vec![];
+            if let StagedKind::File { .. } = entry.kind {
+                stage.open(entry).await?.read_to_end(&mut contents).await?;
+            }
+            recorded.push(Recorded {
+                path: entry.path.clone(),
+                kind: entry.kind.clone(),
+                mode: entry.mode,
+                contents,
+                contents_path: stage.contents_path(entry),
+            });
+        }
+        self.flushes.lock().unwrap().push(recorded);
+        summary.snapshot_id = Some("recorded".to_string());
+        Ok(())
+    }
+}
+
+type Disk = StagedFloppyDisk<RecordingSink>;
+
+fn len(metadata: &StagedMetadata) -> u64 {
+    FloppyMetadata::<Disk>::len(metadata)
+}
+
+fn is_symlink(metadata: &StagedMetadata) -> bool {
+    FloppyMetadata::<Disk>::is_symlink(metadata)
+}
+
+fn big_file() -> Vec<u8> {
+    (0..200_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+async fn source() -> eyre::Result<MemFloppyDisk> {
+    let src = MemFloppyDisk::new();
+    src.create_dir_all("/a/b").await?;
+    src.write("/a/b/f.txt", "hello").await?;
+    src.set_permissions("/a/b/f.txt", MemPermissions::from_mode(0o640))
+        .await?;
+    src.write("/a/g.bin", big_file()).await?;
+    src.symlink(PathBuf::from("b/f.txt"), PathBuf::from("/a/link"))
+        .await?;
+    Ok(src)
+}
+
+#[tokio::test]
+async fn copy_between_hands_the_sink_everything() -> eyre::Result<()> {
+    let src = source().await?;
+    let dest = StagedFloppyDisk::new(RecordingSink::default());
+
+    let summary = DiskDrive::copy_between(&src, &dest).await?;
+    assert_eq!(
+        summary,
+        CopySummary {
+            files: 2,
+            dirs: 2,
+            symlinks: 1,
+            bytes: 5 + 200_000,
+            snapshot_id: Some("recorded".to_string()),
+        }
+    );
+
+    let flushes = dest.sink().flushes.lock().unwrap().clone();
+    assert_eq!(flushes.len(), 1);
+    let entries = &flushes[0];
+    let paths: Vec<_> = entries.iter().map(|e| e.path.clone()).collect();
+    assert_eq!(
+        paths,
+        ["/a", "/a/b", "/a/b/f.txt", "/a/g.bin", "/a/link"]
+            .map(PathBuf::from)
+            .to_vec()
+    );
+
+    assert_eq!(entries[0].kind, StagedKind::Dir);
+    assert_eq!(entries[2].kind, StagedKind::File { len: 5 });
+    assert_eq!(entries[2].mode, 0o640);
+    assert_eq!(entries[2].contents, b"hello");
+    assert_eq!(entries[3].kind, StagedKind::File { len: 200_000 });
+    assert_eq!(entries[3].contents, big_file());
+    assert_eq!(
+        entries[4].kind,
+        StagedKind::Symlink {
+            target: "b/f.txt".into()
+        }
+    );
+
+    // file contents were spilled to disk, not kept in memory
+    let spilled = entries[3].contents_path.clone().unwrap();
+    assert!(entries[4].contents_path.is_none());
+    assert!(!spilled.exists(), "spill isn't cleared after finalize");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn each_copy_is_flushed_on_its_own() -> eyre::Result<()> {
+    let src = source().await?;
+    let dest = StagedFloppyDisk::new(RecordingSink::default());
+    DiskDrive::copy_between(&src, &dest).await?;
+
+    let other = MemFloppyDisk::new();
+    other.write("/only.txt", "second").await?;
+    let summary = DiskDrive::copy_between(&other, &dest).await?;
+    assert_eq!(summary.files, 1);
+    assert_eq!(summary.dirs, 0);
+    assert_eq!(summary.bytes, 6);
+
+    let flushes = dest.sink().flushes.lock().unwrap().clone();
+    assert_eq!(flushes.len(), 2);
+    assert_eq!(flushes[1].len(), 1);
+    assert_eq!(flushes[1][0].path, PathBuf::from("/only.txt"));
+    assert_eq!(flushes[1][0].contents, b"second");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_copies_are_not_flushed_again() -> eyre::Result<()> {
+    let src = source().await?;
+    let dest = StagedFloppyDisk::new(RecordingSink::default());
+
+    // one that fails to flush
+    dest.sink().fail.store(true, Ordering::SeqCst);
+    assert!(DiskDrive::copy_between(&src, &dest).await.is_err());
+
+    // and one that fails partway, with some of it already staged
+    let unreadable = source().await?;
+    unreadable.write("/z.txt", "unreadable").await?;
+    unreadable
+        .set_permissions("/z.txt", MemPermissions::from_mode(0o000))
+        .await?;
+    assert!(DiskDrive::copy_between(&unreadable, &dest).await.is_err());
+
+    let other = MemFloppyDisk::new();
+    other.write("/only.txt", "second").await?;
+    DiskDrive::copy_between(&other, &dest).await?;
+
+    let flushes = dest.sink().flushes.lock().unwrap().clone();
+    assert_eq!(flushes.len(), 1);
+    let paths: Vec<_> = flushes[0].iter().map(|e| e.path.clone()).collect();
+    assert_eq!(paths, [PathBuf::from("/only.txt")]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn staged_files_behave_like_files() -> eyre::Result<()> {
+    let disk = StagedFloppyDisk::new(RecordingSink::default());
+    disk.create_dir_all("/d/e").await?;
+    disk.write("/d/e/x", "contents").await?;
+    assert_eq!(disk.read_to_string("/d/e/x").await?, "contents");
+    assert_eq!(len(&disk.metadata("/d/e/x").await?), 8);
+
+    // reads through a symlink land on the same contents
+    disk.symlink(PathBuf::from("e/x"), PathBuf::from("/d/link"))
+        .await?;
+    assert_eq!(disk.read("/d/link").await?, b"contents");
+    assert!(is_symlink(&disk.symlink_metadata("/d/link").await?));
+
+    disk.copy("/d/e/x", "/d/y").await?;
+    disk.rename("/d/e", "/d/f").await?;
+    assert_eq!(disk.read("/d/f/x").await?, b"contents");
+    assert_eq!(disk.read("/d/y").await?, b"contents");
+    assert!(disk.read("/d/e/x").await.is_err());
+
+    let mut entries = disk.read_dir("/d/f").await?;
+    let entry = FloppyReadDir::<Disk>::next_entry(&mut entries)
+        .await?
+        .unwrap();
+    assert_eq!(FloppyDirEntry::<Disk>::file_name(&entry), "x");
+    assert_eq!(len(&FloppyDirEntry::<Disk>::metadata(&entry).await?), 8);
+
+    // replacing a file replaces its contents too
+    disk.write("/d/z", "zzz").await?;
+    disk.rename("/d/z", "/d/y").await?;
+    assert_eq!(disk.read("/d/y").await?, b"zzz");
+
+    disk.remove_file("/d/y").await?;
+    assert!(disk.read("/d/y").await.is_err());
+    disk.remove_dir_all("/d/f").await?;
+    assert!(!disk.try_exists("/d/f").await?);
+
+    assert!(disk.hard_link("/d/link", "/d/hard").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_only_dirs_are_cleared_after_finalize() -> eyre::Result<()> {
+    let disk = StagedFloppyDisk::new(RecordingSink::default());
+    disk.create_dir_all("/ro/inner").await?;
+    disk.write("/ro/inner/f.txt", "inside").await?;
+    disk.set_permissions("/ro/inner", MemPermissions::from_mode(0o500))
+        .await?;
+    disk.set_permissions("/ro", MemPermissions::from_mode(0o555))
+        .await?;
+    disk.finalize(&mut CopySummary::default()).await?;
+    assert!(!disk.try_exists("/ro").await?);
+    Ok(())
+}